@@ -0,0 +1,60 @@
+// Compares deduplicating hashes by the strings they were written as with
+// deduplicating them by `HashKey`, on a stream shaped like the one
+// `nixpkgs-hashes` sees, where most hashes are repeats and the same digest
+// is written in several encodings.
+//
+//     cargo bench --bench hash_key
+
+use std::collections::HashSet;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use nixapi::hash::{Hash, HashAlgo, HashFormat, HashKey};
+
+const UNIQUE: usize = 10_000;
+const REPEATS: usize = 20;
+const ITERATIONS: u32 = 10;
+
+fn hashes() -> Vec<String> {
+    let formats = [HashFormat::Base16, HashFormat::Nix32, HashFormat::Sri];
+    let mut hashes = Vec::with_capacity(UNIQUE * REPEATS);
+    for repeat in 0..REPEATS {
+        for i in 0..UNIQUE {
+            let hash = Hash::compute(HashAlgo::Sha256, i.to_le_bytes());
+            let format = &formats[(i + repeat) % formats.len()];
+            hashes.push(hash.to_string(format, false));
+        }
+    }
+    hashes
+}
+
+fn bench(name: &str, hashes: &[String], dedup: impl Fn(&[String]) -> usize) {
+    println!("{name:<10} {} unique", dedup(hashes));
+    let mut total = Duration::ZERO;
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        black_box(dedup(black_box(hashes)));
+        total += start.elapsed();
+    }
+    let per_hash = total / ITERATIONS / hashes.len() as u32;
+    println!("{name:<10} {per_hash:>10.2?}/hash");
+}
+
+fn main() {
+    let hashes = hashes();
+    println!("{} hashes, {UNIQUE} digests", hashes.len());
+    bench("string", &hashes, |hashes| {
+        let mut unique = HashSet::new();
+        hashes
+            .iter()
+            .filter(|hash| unique.insert(hash.as_str()))
+            .count()
+    });
+    bench("hash_key", &hashes, |hashes| {
+        let mut unique = HashSet::<HashKey>::new();
+        hashes
+            .iter()
+            .filter(|hash| unique.insert(Hash::parse_csv_record(hash, "sha256").unwrap().key()))
+            .count()
+    });
+}
@@ -0,0 +1,35 @@
+// Compares `Hash::parse_csv_record` with `Hash::parse_as` on each encoding
+// that `nixpkgs-hashes` writes.
+//
+//     cargo bench --bench hash_parse
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use nixapi::hash::{Hash, HashAlgo, HashFormat};
+
+const ITERATIONS: u32 = 1_000_000;
+
+fn bench(name: &str, input: &str, parse: impl Fn(&str) -> Hash) {
+    // Once to warm up, and to check that the hash parses at all.
+    black_box(parse(input));
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(parse(black_box(input)));
+    }
+    let per_iter = start.elapsed() / ITERATIONS;
+    println!("{name:<24} {per_iter:>10.2?}/iter");
+}
+
+fn main() {
+    let hash = Hash::compute(HashAlgo::Sha256, b"");
+    for format in [HashFormat::Base16, HashFormat::Nix32, HashFormat::Sri] {
+        let input = hash.to_string(&format, false);
+        bench(&format!("parse_csv_record {format}"), &input, |input| {
+            Hash::parse_csv_record(input, "sha256").unwrap()
+        });
+        bench(&format!("parse_as {format}"), &input, |input| {
+            Hash::parse_as(input, HashAlgo::Sha256).unwrap()
+        });
+    }
+}
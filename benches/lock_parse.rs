@@ -0,0 +1,77 @@
+// Compares `LockFile::from_slice_fast` with `serde_json` on a generated lock
+// with a few thousand nodes, which is larger than any real flake's lock.
+//
+//     cargo bench --features sonic --bench lock_parse
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use nixapi::flake::lock::LockFile;
+use serde_json::{Map, Value, json};
+
+const NODES: usize = 4000;
+const ITERATIONS: u32 = 50;
+
+fn large_lock() -> Vec<u8> {
+    let mut nodes = Map::new();
+    let mut root_inputs = Map::new();
+    for i in 0..NODES {
+        let name = format!("input-{i}");
+        let mut inputs = Map::new();
+        // Every input follows the one before it, like `nixpkgs` usually is.
+        if i > 0 {
+            inputs.insert("nixpkgs".into(), json!([format!("input-{}", i - 1)]));
+        }
+        let node = json!({
+            "inputs": inputs,
+            "locked": {
+                "lastModified": 1736200483 + i,
+                "narHash": "sha256-JO+lFN2HsCwSLMUWXHeOad6QUxOuwe9UOAF/iSl1J4I=",
+                "owner": "owner",
+                "repo": name,
+                "rev": format!("{i:040x}"),
+                "type": "github"
+            },
+            "original": {
+                "owner": "owner",
+                "repo": name,
+                "type": "github"
+            }
+        });
+        root_inputs.insert(name.clone(), Value::String(name.clone()));
+        nodes.insert(name, node);
+    }
+    nodes.insert("root".into(), json!({ "inputs": root_inputs }));
+    let lock = json!({ "nodes": nodes, "root": "root", "version": 7 });
+    serde_json::to_vec_pretty(&lock).unwrap()
+}
+
+fn bench(name: &str, json: &[u8], parse: impl Fn(&[u8]) -> LockFile) {
+    // Once to warm up, and to check that the lock parses at all.
+    black_box(parse(json));
+    let mut total = Duration::ZERO;
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        black_box(parse(black_box(json)));
+        total += start.elapsed();
+    }
+    let per_iter = total / ITERATIONS;
+    let throughput = json.len() as f64 / per_iter.as_secs_f64() / (1024.0 * 1024.0);
+    println!("{name:<12} {per_iter:>12.2?}/iter {throughput:>8.1} MiB/s");
+}
+
+fn main() {
+    let json = large_lock();
+    println!("{} nodes, {} KiB", NODES + 1, json.len() / 1024);
+    // A faster parser is no use if it reads the lock differently.
+    assert_eq!(
+        LockFile::from_slice_fast(&json).unwrap(),
+        serde_json::from_slice::<LockFile>(&json).unwrap()
+    );
+    bench("serde_json", &json, |json| {
+        serde_json::from_slice(json).unwrap()
+    });
+    bench("sonic_rs", &json, |json| {
+        LockFile::from_slice_fast(json).unwrap()
+    });
+}
@@ -1,38 +1,139 @@
-use std::collections::HashSet;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
-use std::os::unix::process::ExitStatusExt;
-use std::process::{ExitStatus, Stdio};
-use std::sync::{Arc, LazyLock};
+use std::io::{ErrorKind, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
 
 use humantime::{FormattedDuration, format_duration};
 use include_dir::{Dir, include_dir};
-use smol::fs::File;
+use nixapi::derivation::Derivation;
+use nixapi::hash::HashKey;
+use nixapi::process::{ExitStatusError, check_status};
+use smol::fs::{File, OpenOptions};
 use smol::future::try_zip;
-use smol::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
-use smol::lock::Semaphore;
+use smol::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
+};
+use smol::lock::{Semaphore, SemaphoreGuardArc};
 use smol::process::Command;
-use smol::stream::{Stream, StreamExt, try_unfold};
-use smol::{LocalExecutor, channel};
-use sonic_rs::{JsonValueTrait, LazyValue, PointerTree};
+use smol::stream::{BoxedLocal, Stream, StreamExt, try_unfold};
+use smol::{LocalExecutor, Unblock, channel};
+use sonic_rs::{JsonValueTrait, LazyValue};
 use tempfile::TempDir;
 
 static NPINS_DIR: Dir = include_dir!("$CARGO_MANIFEST_DIR/src/nixpkgs-hashes/npins");
 static JOBS_EXPR: &str = include_str!("nixpkgs-release.nix");
 
 static GENERATE_OUTPUT_FILE_NAME: &str = "nixpkgs-hashes.csv";
+static CHECKPOINT_FILE_NAME: &str = "nixpkgs-hashes.ckpt";
 const STORE_PATHS_PER_QUERY: usize = 8;
 const MAX_CONCURRENT_STORE_QUERIES: usize = 8;
 
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Args {
+    count_only: bool,
+    drvs_from: Option<PathBuf>,
+    spill_threshold: Option<usize>,
+    resume: bool,
+    with_origin: bool,
+    header: bool,
+    ordered: bool,
+    max_chunks: Option<usize>,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 struct Hash {
     pub hash: String,
     pub algo: Option<String>,
+    pub mode: HashMode,
+}
+
+// What a fixed-output hash was computed over, from `outputHashMode` or from
+// the `method` of an output. Derivations that don't set it are flat.
+// <https://github.com/NixOS/nix/blob/c9211b0b2d52a26ed666780b763b39a5bddd3fb3/src/libstore/content-address.cc>
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+enum HashMode {
+    #[default]
+    Flat,
+    Recursive,
+    Text,
+    Git,
+}
+
+// A row of the output file. The origin is only recorded with `--with-origin`,
+// and is either `env` for a declared `outputHash` or the name of an output.
+// The attribute path of the job that the derivation was reached from is
+// recorded along with it, when the job came from `nix-eval-jobs`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct HashRecord {
+    pub hash: Hash,
+    pub origin: Option<String>,
+    pub attr: Option<String>,
+}
+
+// An attribute path, if known, and the derivation it evaluated to.
+type Job = (Option<String>, String);
+
+// The dispatch index of a chunk, its jobs, their hashes, and the permit its
+// query was made with.
+type Chunk = (
+    usize,
+    Vec<Job>,
+    std::io::Result<Vec<(String, DerivationHashes)>>,
+    SemaphoreGuardArc,
+);
+
+enum UniqueHashes {
+    Memory(RecordSet),
+    Spill(SpilledHashes),
+}
+
+// Records deduplicated by their digest, mode and origin, keeping the first
+// record inserted for each. The same digest is often written in several
+// encodings across nixpkgs. Hashes that `nixapi` can't read are only
+// deduplicated when they are written the same way.
+#[derive(Default)]
+struct RecordSet {
+    digests: HashMap<(HashKey, HashMode), Origins>,
+    unparsed: HashMap<Hash, Origins>,
+    len: usize,
+}
+
+type Origins = HashMap<Option<String>, HashRecord>;
+
+// Hashes are kept in memory until `threshold` is reached, then written out as a
+// sorted run of CSV records. Runs are merged and deduplicated at the end.
+struct SpilledHashes {
+    dir: TempDir,
+    threshold: usize,
+    pending: RecordSet,
+    runs: Vec<PathBuf>,
+    spilled: usize,
+}
+
+#[derive(Debug, thiserror::Error)]
+enum DrvParseError {
+    #[error("malformed JSON: {0}")]
+    Json(#[from] sonic_rs::Error),
+    #[error("expected `{0}` to be a string")]
+    NotAString(&'static str),
+    #[error("output `{0}` has a `hash` but no `hashAlgo`")]
+    MissingHashAlgo(String),
+    #[error("unknown hash mode `{0}`")]
+    UnknownHashMode(String),
+    #[error("line has neither a `drvPath` nor an `error`")]
+    UnexpectedJob,
 }
 
+#[derive(Debug, PartialEq)]
 struct DerivationHashes {
     pub env: Option<Hash>,
     pub outputs: Vec<(String, Hash)>,
+    pub inputs: Vec<String>,
 }
 
 enum Statistic {
@@ -40,9 +141,28 @@ enum Statistic {
         drvs: usize,
         hashes: usize,
         total_unique: usize,
+        unique_algos: AlgoCounts,
     },
 }
 
+#[derive(Debug, Default, PartialEq, Eq)]
+struct AlgoCounts(BTreeMap<String, u64>);
+
+// Limits how often progress is printed when stderr is not a terminal.
+struct Throttle {
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+// Holds chunks that finished before an earlier one, so that `--ordered` can
+// write them in the order they were dispatched. Each chunk keeps its query
+// permit until it is written, so at most `MAX_CONCURRENT_STORE_QUERIES` are
+// ever pending.
+struct ReorderBuffer<T> {
+    next: usize,
+    pending: BTreeMap<usize, T>,
+}
+
 struct TimingBucket<const SCALE: u64> {
     last_total: u64,
     last_update: Instant,
@@ -51,6 +171,8 @@ struct TimingBucket<const SCALE: u64> {
 }
 
 fn main() -> std::io::Result<()> {
+    let args = Args::parse(std::env::args_os().skip(1))?;
+
     let expr_dir = {
         let dir = TempDir::with_prefix("nixpkgs-release.")?;
         let npins_path = dir.path().join("npins");
@@ -62,6 +184,31 @@ fn main() -> std::io::Result<()> {
     };
     let expr_path = expr_dir.path().canonicalize()?;
 
+    if args.count_only {
+        let count = smol::block_on(async {
+            let drvs = drv_paths(&args, &expr_path).await?;
+            count_drvs(drvs).await
+        })?;
+        println!("{count}");
+        expr_dir.close()?;
+        return Ok(());
+    }
+
+    let (resumed_lines, completed_drvs) = if args.resume {
+        let lines = read_complete_lines(GENERATE_OUTPUT_FILE_NAME)?;
+        let completed = read_complete_lines(CHECKPOINT_FILE_NAME)?;
+        eprintln!(
+            "[resume] {} hashes written, {} derivations completed",
+            strip_csv_header(&lines).len(),
+            completed.len(),
+        );
+        (lines, completed.into_iter().collect())
+    } else {
+        (Vec::new(), HashSet::new())
+    };
+    // A resumed file keeps whatever header it was started with.
+    let write_header = args.header && resumed_lines.is_empty();
+
     println!("STORE_PATHS_PER_QUERY = {STORE_PATHS_PER_QUERY}");
     println!("MAX_CONCURRENT_STORE_QUERIES = {MAX_CONCURRENT_STORE_QUERIES}");
 
@@ -70,167 +217,744 @@ fn main() -> std::io::Result<()> {
     let (chunks_tx, chunks_rx) = channel::unbounded();
     let (stats_tx, stats_rx) = channel::bounded(1);
 
+    // Set once `--max-chunks` have been written, so that no more chunks are
+    // dispatched and the output is flushed.
+    let stop = &AtomicBool::new(false);
+
+    let args = &args;
     let dispatcher = async move {
-        let drvs_expr = OsString::from_iter(["import ".as_ref(), expr_path.as_ref()]);
-        let eval_drvs = nix_eval_jobs(true, drvs_expr).await?;
-        smol::pin!(eval_drvs);
-
-        loop {
-            let mut chunk = (&mut eval_drvs).take(STORE_PATHS_PER_QUERY);
-            let mut batch = Vec::with_capacity(STORE_PATHS_PER_QUERY);
-            while let Some(drv_path) = chunk.try_next().await? {
-                batch.push(drv_path);
-            }
-            if batch.is_empty() {
-                break;
-            }
+        let drvs = skip_completed(drv_paths(args, &expr_path).await?, &completed_drvs);
+        let mut dispatched = 0;
+        for_each_chunk(drvs, STORE_PATHS_PER_QUERY, stop, async |batch| {
             let permit = sem.acquire_arc().await;
             let tx = chunks_tx.clone();
+            let index = dispatched;
+            dispatched += 1;
             ex.spawn(async move {
-                let hashes = collect_hashes_for_many_derivations(batch).await;
-                tx.send(hashes).await.unwrap();
-                drop(permit);
+                let drv_paths = batch.iter().map(|(_, drv_path)| drv_path.as_str());
+                let hashes = collect_hashes_isolating_failures(
+                    &drv_paths.collect::<Vec<_>>(),
+                    async |drv_paths| collect_hashes_for_many_derivations(drv_paths).await,
+                )
+                .await;
+                // The permit goes with the chunk, and is released once it is
+                // written. The receiver is gone if it was stopped.
+                tx.send((index, batch, hashes, permit)).await.ok();
             })
             .detach();
-        }
-
-        Ok::<_, std::io::Error>(())
+        })
+        .await
     };
 
     let receiver = async move {
-        let output_file = File::create(GENERATE_OUTPUT_FILE_NAME).await?;
-        let mut writer = BufWriter::new(output_file);
-        let mut unique = HashSet::new();
-
-        let mut write_unique_hash = async |unique: &mut HashSet<_>, hash: &Hash| {
-            if unique.insert(hash.clone()) {
-                let csv_record = hash.to_csv_record().to_string();
-                writer.write_all(csv_record.as_bytes()).await?;
-                writer.write_all(b"\n").await?;
-            }
-            Ok::<_, std::io::Error>(())
+        let mut writer = open_output(GENERATE_OUTPUT_FILE_NAME, args.resume).await?;
+        if write_header {
+            writer
+                .write_all(csv_header(args.with_origin).as_bytes())
+                .await?;
+            writer.write_all(b"\n").await?;
+        }
+        let mut output = HashOutput {
+            writer,
+            checkpoint: open_output(CHECKPOINT_FILE_NAME, args.resume).await?,
+            checkpointing: args.spill_threshold.is_none(),
+            unique: if args.resume {
+                UniqueHashes::resume(strip_csv_header(&resumed_lines), args.with_origin)?
+            } else {
+                UniqueHashes::new(args.spill_threshold)?
+            },
+            with_origin: args.with_origin,
         };
 
-        while let Ok(res) = chunks_rx.recv().await {
-            let drv_hashes = res?;
-            let mut hash_count = 0;
-            let drv_count = drv_hashes.len();
-
-            for (_drv_path, DerivationHashes { env, outputs }) in drv_hashes {
-                if let Some(env_hash) = env {
-                    write_unique_hash(&mut unique, &env_hash).await?;
-                    hash_count += 1;
-                }
-                for (_out_name, out_hash) in outputs {
-                    write_unique_hash(&mut unique, &out_hash).await?;
-                    hash_count += 1;
-                }
-            }
+        write_chunks(
+            chunks_rx,
+            &mut output,
+            args.ordered,
+            args.max_chunks,
+            stop,
+            &stats_tx,
+        )
+        .await?;
 
+        let (total_unique, unique_algos) = output.finish().await?;
+        if !unique_algos.0.is_empty() {
             stats_tx
                 .send(Statistic::Progress {
-                    drvs: drv_count,
-                    hashes: hash_count,
-                    total_unique: unique.len(),
+                    drvs: 0,
+                    hashes: 0,
+                    total_unique,
+                    unique_algos,
                 })
                 .await
                 .unwrap();
         }
 
-        writer.close().await?;
-        Ok::<_, std::io::Error>(unique)
+        Ok::<_, std::io::Error>(total_unique)
     };
 
     let statistics = async move {
         let mut total_drvs = 0;
         let mut total_hashes = 0;
+        let mut total_algos = AlgoCounts::default();
         let start = Instant::now();
 
         let mut time_1k = TimingBucket::<1_000>::new(start);
         let mut time_10k = TimingBucket::<10_000>::new(start);
         let mut time_100k = TimingBucket::<100_000>::new(start);
 
+        // A terminal gets one progress line that is redrawn on every update.
+        // The perf stats would make it wrap, and only the last row of a
+        // wrapped line is cleared, so they are logged above it every few
+        // seconds instead. Logs get the whole status at most once a second.
+        // Either way, the last one skipped is printed at the end.
+        let interactive = std::io::stderr().is_terminal();
+        let clear_line = if interactive { "\r\x1b[2K" } else { "" };
+        let mut throttle = Throttle::new(Duration::from_secs(if interactive { 10 } else { 1 }));
+        let mut drawn = false;
+        let mut skipped = None;
+
         while let Ok(msg) = stats_rx.recv().await {
             match msg {
                 Statistic::Progress {
                     drvs,
                     hashes,
                     total_unique,
+                    unique_algos,
                 } => {
                     total_hashes += hashes as u64;
                     total_drvs += drvs as u64;
+                    total_algos.merge(&unique_algos);
                     let now = Instant::now();
 
                     time_1k.update(now, total_hashes);
                     time_10k.update(now, total_hashes);
                     time_100k.update(now, total_hashes);
 
-                    eprintln!(
+                    let progress = format!(
                         "[progress] drvs: {total_drvs}, hashes: {total_hashes} (unique: {total_unique}), elapsed: {}",
                         DisplayElapsed::from(now - start),
                     );
-                    eprintln!(
+                    let perf = format!(
                         "[perf (s/hash)] {time_1k:>width_0$}, {time_10k:>width_1$}, {time_100k:>#width_2$}",
                         width_0 = 9,
                         width_1 = 10,
                         width_2 = 12,
                     );
+                    let logged = if interactive {
+                        perf
+                    } else {
+                        format!("{progress} {perf}")
+                    };
+                    if throttle.ready(now) {
+                        eprintln!("{clear_line}{logged}");
+                        skipped = None;
+                    } else {
+                        skipped = Some(logged);
+                    }
+                    if interactive {
+                        eprint!("{clear_line}{progress}");
+                        drawn = true;
+                    }
                 }
             }
         }
+
+        if drawn {
+            eprintln!();
+        }
+        if let Some(status) = skipped {
+            eprintln!("{status}");
+        }
+        eprintln!("[algos] {total_algos}");
     };
 
-    let _hashes = smol::block_on(ex.run(async {
+    smol::block_on(ex.run(async {
         let statistics_ = ex.spawn(statistics);
-        let (_, hashes) = try_zip(dispatcher, receiver).await?;
+        let (_, total_unique) = try_zip(dispatcher, receiver).await?;
         statistics_.await;
-        eprintln!("[finish] done collecting {} unique hashes", hashes.len());
-        Ok::<_, std::io::Error>(hashes)
+        eprintln!("[finish] done collecting {total_unique} unique hashes");
+        Ok::<_, std::io::Error>(())
     }))?;
 
     expr_dir.close()?;
     Ok(())
 }
 
-impl Hash {
+impl Args {
+    fn parse(args: impl IntoIterator<Item = OsString>) -> std::io::Result<Self> {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--count-only") => parsed.count_only = true,
+                Some("--drvs-from") => {
+                    let path = args.next().ok_or_else(|| {
+                        std::io::Error::new(ErrorKind::InvalidInput, "--drvs-from requires a path")
+                    })?;
+                    parsed.drvs_from = Some(path.into());
+                }
+                Some("--spill-to-disk") => {
+                    let threshold = args
+                        .next()
+                        .and_then(|arg| arg.to_str()?.parse().ok())
+                        .filter(|&threshold| threshold > 0)
+                        .ok_or_else(|| {
+                            std::io::Error::new(
+                                ErrorKind::InvalidInput,
+                                "--spill-to-disk requires a positive number of hashes",
+                            )
+                        })?;
+                    parsed.spill_threshold = Some(threshold);
+                }
+                Some("--resume") => parsed.resume = true,
+                Some("--with-origin") => parsed.with_origin = true,
+                Some("--header") => parsed.header = true,
+                Some("--ordered") => parsed.ordered = true,
+                Some("--max-chunks") => {
+                    let max_chunks = args
+                        .next()
+                        .and_then(|arg| arg.to_str()?.parse().ok())
+                        .filter(|&max_chunks| max_chunks > 0)
+                        .ok_or_else(|| {
+                            std::io::Error::new(
+                                ErrorKind::InvalidInput,
+                                "--max-chunks requires a positive number of chunks",
+                            )
+                        })?;
+                    parsed.max_chunks = Some(max_chunks);
+                }
+                _ => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("unrecognized argument: {}", arg.display()),
+                    ));
+                }
+            }
+        }
+        if parsed.resume && parsed.spill_threshold.is_some() {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "--resume cannot be combined with --spill-to-disk",
+            ));
+        }
+        Ok(parsed)
+    }
+}
+
+fn jobs_expr(expr_path: &Path) -> OsString {
+    OsString::from_iter(["import ".as_ref(), expr_path.as_os_str()])
+}
+
+impl HashRecord {
     fn to_csv_record(&self) -> impl std::fmt::Display {
-        struct __Display<'a>(&'a Hash);
+        struct __Display<'a>(&'a HashRecord);
         impl<'a> std::fmt::Display for __Display<'a> {
             fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-                write!(f, r#""{}""#, self.0.hash)?;
+                write_csv_field(f, Some(&self.0.hash.hash))?;
                 write!(f, ", ")?;
-                match &self.0.algo {
-                    Some(algo) => write!(f, r#""{algo}""#)?,
-                    None => write!(f, "null")?,
+                write_csv_field(f, self.0.hash.algo.as_deref())?;
+                write!(f, ", ")?;
+                write_csv_field(f, Some(self.0.hash.mode.as_str()))?;
+                if let Some(origin) = &self.0.origin {
+                    write!(f, ", ")?;
+                    write_csv_field(f, Some(origin))?;
+                    write!(f, ", ")?;
+                    write_csv_field(f, self.0.attr.as_deref())?;
                 }
                 Ok(())
             }
         }
         __Display(self)
     }
+
+    fn from_csv_record(record: &str) -> Option<Self> {
+        let mut fields = csv_fields(record)?.into_iter();
+        let hash = fields.next()??;
+        let algo = fields.next()?;
+        let mode = HashMode::from_output_hash_mode(&fields.next()??)?;
+        let (origin, attr) = match fields.next() {
+            Some(origin) => (Some(origin?), fields.next()?),
+            None => (None, None),
+        };
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            hash: Hash { hash, algo, mode },
+            origin,
+            attr,
+        })
+    }
+
+    // Orders spilled records so that the ones `RecordSet` would deduplicate
+    // sort next to each other. Readable hashes are written as `algo:nix32`,
+    // which no unreadable hash can be. Attribute paths only say where a hash
+    // was reached from, so they are left out.
+    fn sort_key(&self) -> String {
+        let hash = match self.hash.digest() {
+            Some(digest) => Hash {
+                hash: format!("{digest:#}"),
+                algo: None,
+                mode: self.hash.mode,
+            },
+            None => self.hash.clone(),
+        };
+        let key = HashRecord {
+            hash,
+            origin: self.origin.clone(),
+            attr: None,
+        };
+        key.to_csv_record().to_string()
+    }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-struct ExitStatusError(ExitStatus);
+impl Hash {
+    // Hashes without an algorithm of their own are only readable with one as
+    // a prefix.
+    fn digest(&self) -> Option<nixapi::hash::Hash> {
+        match &self.algo {
+            Some(algo) => nixapi::hash::Hash::parse_csv_record(&self.hash, algo),
+            None => nixapi::hash::Hash::parse(&self.hash),
+        }
+        .ok()
+    }
+}
 
-impl std::error::Error for ExitStatusError {}
+// Fields are quoted, with any quotes inside doubled, or `null` when absent.
+// Attribute paths can contain both quotes and commas, such as
+// `nodePackages."@angular/cli"`.
+fn write_csv_field(f: &mut std::fmt::Formatter<'_>, field: Option<&str>) -> std::fmt::Result {
+    let Some(field) = field else {
+        return write!(f, "null");
+    };
+    write!(f, "\"")?;
+    for (i, part) in field.split('"').enumerate() {
+        if i > 0 {
+            write!(f, "\"\"")?;
+        }
+        write!(f, "{part}")?;
+    }
+    write!(f, "\"")
+}
 
-impl std::fmt::Display for ExitStatusError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if let Some(code) = self.0.code() {
-            write!(f, "exited with code: {code}")
-        } else if let Some(signal) = self.0.signal() {
-            write!(f, "killed by signal: {signal}")
+// Splits a record written by `HashRecord::to_csv_record` into its fields.
+fn csv_fields(mut record: &str) -> Option<Vec<Option<String>>> {
+    let mut fields = Vec::new();
+    loop {
+        if let Some(rest) = record.strip_prefix("null") {
+            fields.push(None);
+            record = rest;
         } else {
-            write!(f, "exited with status: {}", self.0)
+            let mut field = String::new();
+            record = record.strip_prefix('"')?;
+            loop {
+                let end = record.find('"')?;
+                field.push_str(&record[..end]);
+                record = &record[end + 1..];
+                match record.strip_prefix('"') {
+                    Some(rest) => {
+                        field.push('"');
+                        record = rest;
+                    }
+                    None => break,
+                }
+            }
+            fields.push(Some(field));
+        }
+        if record.is_empty() {
+            return Some(fields);
         }
+        record = record.strip_prefix(", ")?;
+    }
+}
+
+fn csv_header(with_origin: bool) -> &'static str {
+    if with_origin {
+        "hash, algo, mode, origin, attr"
+    } else {
+        "hash, algo, mode"
+    }
+}
+
+fn strip_csv_header(lines: &[String]) -> &[String] {
+    match lines.split_first() {
+        Some((first, records)) if first == csv_header(false) || first == csv_header(true) => {
+            records
+        }
+        _ => lines,
+    }
+}
+
+// Reads the newline-terminated lines of a file left by an earlier run,
+// truncating any partial line written when that run was interrupted.
+fn read_complete_lines(path: impl AsRef<Path>) -> std::io::Result<Vec<String>> {
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let complete = contents.rfind('\n').map_or(0, |end| end + 1);
+    if complete < contents.len() {
+        let file = std::fs::OpenOptions::new().write(true).open(&path)?;
+        file.set_len(complete as u64)?;
+    }
+    Ok(contents[..complete].lines().map(str::to_string).collect())
+}
+
+async fn open_output(path: impl AsRef<Path>, append: bool) -> std::io::Result<BufWriter<File>> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .await?;
+    Ok(BufWriter::new(file))
+}
+
+fn skip_completed<'a>(
+    drvs: impl Stream<Item = std::io::Result<Job>> + 'a,
+    completed: &'a HashSet<String>,
+) -> impl Stream<Item = std::io::Result<Job>> + 'a {
+    drvs.filter(move |res| !matches!(res, Ok((_, drv_path)) if completed.contains(drv_path)))
+}
+
+// The output and checkpoint files, along with the hashes already in them.
+struct HashOutput {
+    writer: BufWriter<File>,
+    checkpoint: BufWriter<File>,
+    // Spilled records only reach the output file at the end, so completed
+    // chunks can't be checkpointed as they arrive.
+    checkpointing: bool,
+    unique: UniqueHashes,
+    with_origin: bool,
+}
+
+impl HashOutput {
+    // Returns how many hashes the chunk had, and the algorithms of the ones
+    // that were new.
+    async fn write_chunk(
+        &mut self,
+        batch: &[Job],
+        drv_hashes: Vec<(String, DerivationHashes)>,
+    ) -> std::io::Result<(usize, AlgoCounts)> {
+        let mut hash_count = 0;
+        let mut unique_algos = AlgoCounts::default();
+        let with_origin = self.with_origin;
+        let origin = |name: &str| with_origin.then(|| name.to_string());
+        let attrs = if with_origin {
+            closure_attrs(batch, &drv_hashes)
+        } else {
+            HashMap::new()
+        };
+        for (drv_path, DerivationHashes { env, outputs, .. }) in drv_hashes {
+            let attr = attrs.get(&drv_path).map(|attr| attr.to_string());
+            if let Some(hash) = env {
+                let record = HashRecord {
+                    hash,
+                    origin: origin("env"),
+                    attr: attr.clone(),
+                };
+                self.write_unique_hash(&mut unique_algos, &record).await?;
+                hash_count += 1;
+            }
+            for (out_name, hash) in outputs {
+                let record = HashRecord {
+                    hash,
+                    origin: origin(&out_name),
+                    attr: attr.clone(),
+                };
+                self.write_unique_hash(&mut unique_algos, &record).await?;
+                hash_count += 1;
+            }
+        }
+
+        // A chunk is only recorded once its hashes are on disk. Anything
+        // written without being checkpointed is reprocessed and deduplicated.
+        if self.checkpointing {
+            self.writer.flush().await?;
+            for (_, drv_path) in batch {
+                self.checkpoint.write_all(drv_path.as_bytes()).await?;
+                self.checkpoint.write_all(b"\n").await?;
+            }
+            self.checkpoint.flush().await?;
+        }
+        Ok((hash_count, unique_algos))
+    }
+
+    async fn write_unique_hash(
+        &mut self,
+        algos: &mut AlgoCounts,
+        record: &HashRecord,
+    ) -> std::io::Result<()> {
+        if self.unique.insert(record).await? {
+            let csv_record = record.to_csv_record().to_string();
+            self.writer.write_all(csv_record.as_bytes()).await?;
+            self.writer.write_all(b"\n").await?;
+            algos.add(record.hash.algo.as_deref());
+        }
+        Ok(())
+    }
+
+    async fn finish(mut self) -> std::io::Result<(usize, AlgoCounts)> {
+        let finished = self.unique.finish(&mut self.writer).await?;
+        self.writer.close().await?;
+        self.checkpoint.close().await?;
+        Ok(finished)
+    }
+}
+
+// Maps each derivation in the closures of a chunk to the attribute path of the
+// first job in it that depends on the derivation. Derivations shared between
+// chunks are mapped again for each of them, but their hashes are only written
+// with the first attribute path they were reached from.
+fn closure_attrs<'a>(
+    batch: &'a [Job],
+    drv_hashes: &[(String, DerivationHashes)],
+) -> HashMap<String, &'a str> {
+    let inputs = drv_hashes
+        .iter()
+        .map(|(drv_path, hashes)| (drv_path.as_str(), &hashes.inputs))
+        .collect::<HashMap<_, _>>();
+    let mut attrs = HashMap::new();
+    for (attr, drv_path) in batch {
+        let Some(attr) = attr else {
+            continue;
+        };
+        let mut queue = vec![drv_path.as_str()];
+        while let Some(drv_path) = queue.pop() {
+            if attrs.contains_key(drv_path) {
+                continue;
+            }
+            attrs.insert(drv_path.to_string(), attr.as_str());
+            if let Some(inputs) = inputs.get(drv_path) {
+                queue.extend(inputs.iter().map(String::as_str));
+            }
+        }
+    }
+    attrs
+}
+
+impl UniqueHashes {
+    fn new(spill_threshold: Option<usize>) -> std::io::Result<Self> {
+        Ok(match spill_threshold {
+            None => Self::Memory(RecordSet::default()),
+            Some(threshold) => Self::Spill(SpilledHashes {
+                dir: TempDir::with_prefix("nixpkgs-hashes.")?,
+                threshold,
+                pending: RecordSet::default(),
+                runs: Vec::new(),
+                spilled: 0,
+            }),
+        })
+    }
+
+    // Records that don't parse, or that don't match `--with-origin`, were
+    // written by another version or with other arguments. Appending to them
+    // would leave a file with mixed columns, so they are an error.
+    fn resume(records: &[String], with_origin: bool) -> std::io::Result<Self> {
+        let mut unique = RecordSet::default();
+        for csv_record in records {
+            let record = HashRecord::from_csv_record(csv_record)
+                .filter(|record| record.origin.is_some() == with_origin)
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "can't resume from a record with other columns than `{}`: {csv_record}",
+                            csv_header(with_origin),
+                        ),
+                    )
+                })?;
+            unique.insert(&record);
+        }
+        Ok(Self::Memory(unique))
+    }
+
+    // Returns whether the record for `hash` should be written now. Spilled
+    // hashes can't be deduplicated until `finish`, so they are never written
+    // early. Records are only cloned when they are actually inserted, since
+    // most hashes are duplicates.
+    async fn insert(&mut self, record: &HashRecord) -> std::io::Result<bool> {
+        match self {
+            Self::Memory(unique) => Ok(unique.insert(record)),
+            Self::Spill(spilled) => {
+                if spilled.pending.insert(record) && spilled.pending.len >= spilled.threshold {
+                    spilled.spill().await?;
+                }
+                Ok(false)
+            }
+        }
+    }
+
+    // Exact for in-memory sets, an upper bound once hashes have been spilled.
+    fn len(&self) -> usize {
+        match self {
+            Self::Memory(unique) => unique.len,
+            Self::Spill(spilled) => spilled.spilled + spilled.pending.len,
+        }
+    }
+
+    // Writes any deferred records, returning the exact number of unique hashes
+    // and the algorithms of the records written here.
+    async fn finish(
+        self,
+        writer: &mut (impl AsyncWrite + Unpin),
+    ) -> std::io::Result<(usize, AlgoCounts)> {
+        match self {
+            Self::Memory(unique) => Ok((unique.len, AlgoCounts::default())),
+            Self::Spill(spilled) => spilled.merge(writer).await,
+        }
+    }
+}
+
+impl RecordSet {
+    // Returns whether the record is new. It is only cloned if it is.
+    fn insert(&mut self, record: &HashRecord) -> bool {
+        let origins = match record.hash.digest() {
+            Some(digest) => self
+                .digests
+                .entry((digest.key(), record.hash.mode))
+                .or_default(),
+            None => match self.unparsed.get_mut(&record.hash) {
+                Some(origins) => origins,
+                None => self.unparsed.entry(record.hash.clone()).or_default(),
+            },
+        };
+        if origins.contains_key(&record.origin) {
+            return false;
+        }
+        origins.insert(record.origin.clone(), record.clone());
+        self.len += 1;
+        true
+    }
+
+    fn drain(&mut self) -> impl Iterator<Item = HashRecord> {
+        self.len = 0;
+        let digests = self.digests.drain().map(|(_, origins)| origins);
+        let unparsed = self.unparsed.drain().map(|(_, origins)| origins);
+        digests.chain(unparsed).flat_map(HashMap::into_values)
+    }
+}
+
+impl SpilledHashes {
+    async fn spill(&mut self) -> std::io::Result<()> {
+        let mut records = self
+            .pending
+            .drain()
+            .map(|record| (record.sort_key(), record.to_csv_record().to_string()))
+            .collect::<Vec<_>>();
+        records.sort_unstable();
+
+        let path = self.dir.path().join(format!("run-{}.csv", self.runs.len()));
+        let mut writer = BufWriter::new(File::create(&path).await?);
+        for (_, record) in &records {
+            writer.write_all(record.as_bytes()).await?;
+            writer.write_all(b"\n").await?;
+        }
+        writer.close().await?;
+
+        self.spilled += records.len();
+        self.runs.push(path);
+        Ok(())
+    }
+
+    async fn merge(
+        mut self,
+        writer: &mut (impl AsyncWrite + Unpin),
+    ) -> std::io::Result<(usize, AlgoCounts)> {
+        self.spill().await?;
+
+        let mut runs = Vec::with_capacity(self.runs.len());
+        for path in &self.runs {
+            runs.push(BufReader::new(File::open(path).await?).lines());
+        }
+        let mut heads = BinaryHeap::new();
+        for (index, run) in runs.iter_mut().enumerate() {
+            if let Some(head) = next_spilled(run).await? {
+                heads.push(Reverse((head, index)));
+            }
+        }
+
+        // Each run has at most one record with a key, and the one that sorts
+        // first is kept.
+        let mut total_unique = 0;
+        let mut algos = AlgoCounts::default();
+        let mut last = None::<String>;
+        while let Some(Reverse(((key, csv_record, algo), index))) = heads.pop() {
+            if let Some(next) = next_spilled(&mut runs[index]).await? {
+                heads.push(Reverse((next, index)));
+            }
+            if last.as_ref() != Some(&key) {
+                writer.write_all(csv_record.as_bytes()).await?;
+                writer.write_all(b"\n").await?;
+                algos.add(algo.as_deref());
+                total_unique += 1;
+                last = Some(key);
+            }
+        }
+
+        drop(runs);
+        self.dir.close()?;
+        Ok((total_unique, algos))
+    }
+}
+
+// Reads the next record of a run, along with its sort key and algorithm.
+async fn next_spilled(
+    run: &mut (impl Stream<Item = std::io::Result<String>> + Unpin),
+) -> std::io::Result<Option<(String, String, Option<String>)>> {
+    let Some(csv_record) = run.next().await.transpose()? else {
+        return Ok(None);
+    };
+    let record = HashRecord::from_csv_record(&csv_record).ok_or_else(|| {
+        std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("malformed spilled record: {csv_record}"),
+        )
+    })?;
+    Ok(Some((record.sort_key(), csv_record, record.hash.algo)))
+}
+
+impl AlgoCounts {
+    fn add(&mut self, algo: Option<&str>) {
+        let algo = algo.unwrap_or("null");
+        match self.0.get_mut(algo) {
+            Some(count) => *count += 1,
+            None => {
+                self.0.insert(algo.to_string(), 1);
+            }
+        }
+    }
+
+    fn merge(&mut self, other: &AlgoCounts) {
+        for (algo, count) in &other.0 {
+            *self.0.entry(algo.clone()).or_default() += count;
+        }
+    }
+}
+
+impl std::fmt::Display for AlgoCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut counts = self.0.iter().collect::<Vec<_>>();
+        counts.sort_by(|(a_algo, a_count), (b_algo, b_count)| {
+            b_count.cmp(a_count).then(a_algo.cmp(b_algo))
+        });
+        let mut counts = counts.into_iter().peekable();
+        while let Some((algo, count)) = counts.next() {
+            write!(f, "{algo}: {count}")?;
+            if counts.peek().is_some() {
+                write!(f, ", ")?;
+            }
+        }
+        Ok(())
     }
 }
 
 async fn nix_eval_jobs(
     force_recurse: bool,
     expr: impl AsRef<OsStr>,
-) -> std::io::Result<impl Stream<Item = std::io::Result<String>>> {
+) -> std::io::Result<impl Stream<Item = std::io::Result<Job>>> {
     let mut cmd = Command::new("nix-eval-jobs");
     if force_recurse {
         cmd.arg("--force-recurse");
@@ -245,109 +969,367 @@ async fn nix_eval_jobs(
 
     let mut proc = cmd.spawn()?;
     let stdout = proc.stdout.take().unwrap();
-    let drv_paths = BufReader::new(stdout).lines().filter_map(|res| {
-        if let Ok(line) = res {
-            if let Ok(drv_path) = sonic_rs::get_from_str(&line, ["drvPath"]) {
-                let drv_path = drv_path.as_str().unwrap().to_string();
-                Some(Ok(drv_path))
-            } else {
-                assert!(sonic_rs::get_from_str(&line, ["error"]).is_ok());
+    let jobs = BufReader::new(stdout).lines().filter_map(|res| match res {
+        Ok(line) => match eval_job(&line) {
+            Ok(job) => job.map(Ok),
+            Err(e) => {
+                eprintln!("[skip] nix-eval-jobs output: {e}");
                 None
             }
+        },
+        Err(e) => Some(Err(e)),
+    });
+
+    let stream = try_unfold((proc, jobs), move |(mut proc, mut jobs)| async move {
+        if let Some(status) = proc.try_status()? {
+            check_status(status).map(|_| None)
+        } else if let Some(job) = jobs.try_next().await? {
+            Ok(Some((job, (proc, jobs))))
         } else {
-            Some(res)
+            let status = proc.status().await?;
+            check_status(status).map(|_| None)
         }
     });
 
-    let check_status = |status: ExitStatus| {
-        if status.success() {
-            Ok(())
-        } else {
-            use std::io::Error;
-            Err(Error::other(ExitStatusError(status)))
+    Ok(stream)
+}
+
+// Jobs that failed to evaluate are reported with an `error` and skipped.
+fn eval_job(line: &str) -> Result<Option<Job>, DrvParseError> {
+    match sonic_rs::get_from_str(line, ["drvPath"]) {
+        Ok(drv_path) => {
+            let drv_path = drv_path
+                .as_str()
+                .ok_or(DrvParseError::NotAString("drvPath"))?;
+            let attr = match sonic_rs::get_from_str(line, ["attr"]) {
+                Ok(attr) => match attr.as_str() {
+                    Some(attr) => Some(attr.to_string()),
+                    None => return Err(DrvParseError::NotAString("attr")),
+                },
+                Err(_) => None,
+            };
+            Ok(Some((attr, drv_path.to_string())))
         }
-    };
-    let stream = try_unfold(
-        (proc, drv_paths),
-        move |(mut proc, mut drv_paths)| async move {
-            if let Some(status) = proc.try_status()? {
-                check_status(status).map(|_| None)
-            } else if let Some(drv_path) = drv_paths.try_next().await? {
-                Ok(Some((drv_path, (proc, drv_paths))))
+        Err(_) if sonic_rs::get_from_str(line, ["error"]).is_ok() => Ok(None),
+        Err(_) => Err(DrvParseError::UnexpectedJob),
+    }
+}
+
+async fn drv_paths(
+    args: &Args,
+    expr_path: &Path,
+) -> std::io::Result<BoxedLocal<std::io::Result<Job>>> {
+    Ok(match &args.drvs_from {
+        Some(path) if path.as_os_str() == "-" => {
+            read_drv_paths(BufReader::new(Unblock::new(std::io::stdin()))).boxed_local()
+        }
+        Some(path) => read_drv_paths(BufReader::new(File::open(path).await?)).boxed_local(),
+        None => nix_eval_jobs(true, jobs_expr(expr_path))
+            .await?
+            .boxed_local(),
+    })
+}
+
+fn read_drv_paths(reader: impl AsyncBufRead + Unpin) -> impl Stream<Item = std::io::Result<Job>> {
+    reader.lines().filter_map(|res| match res {
+        Ok(line) => {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                None
             } else {
-                let status = proc.status().await?;
-                check_status(status).map(|_| None)
+                Some(Ok((None, line.to_string())))
             }
-        },
-    );
+        }
+        Err(e) => Some(Err(e)),
+    })
+}
 
-    Ok(stream)
+async fn for_each_chunk(
+    drvs: impl Stream<Item = std::io::Result<Job>>,
+    chunk_size: usize,
+    stop: &AtomicBool,
+    mut f: impl AsyncFnMut(Vec<Job>),
+) -> std::io::Result<()> {
+    smol::pin!(drvs);
+    while !stop.load(Ordering::Relaxed) {
+        let mut chunk = (&mut drvs).take(chunk_size);
+        let mut batch = Vec::with_capacity(chunk_size);
+        while let Some(job) = chunk.try_next().await? {
+            batch.push(job);
+        }
+        if batch.is_empty() {
+            break;
+        }
+        f(batch).await;
+    }
+    Ok(())
+}
+
+// Writes chunks as they arrive until the dispatcher is done, or until `stop`
+// is set. Setting `stop` also ends dispatching, and it is set here once
+// `max_chunks` have been written.
+async fn write_chunks(
+    chunks: channel::Receiver<Chunk>,
+    output: &mut HashOutput,
+    ordered: bool,
+    max_chunks: Option<usize>,
+    stop: &AtomicBool,
+    stats: &channel::Sender<Statistic>,
+) -> std::io::Result<()> {
+    let mut written = 0;
+    // Chunks finish in whatever order their queries do, unless asked to be
+    // written in the order they were dispatched.
+    let mut reorder = ordered.then(ReorderBuffer::new);
+    while let Ok((index, batch, res, permit)) = chunks.recv().await {
+        let ready = match &mut reorder {
+            Some(reorder) => reorder.push(index, (batch, res, permit)),
+            None => vec![(batch, res, permit)],
+        };
+        for (batch, res, _permit) in ready {
+            let drv_hashes = res?;
+            let drv_count = drv_hashes.len();
+            let (hash_count, unique_algos) = output.write_chunk(&batch, drv_hashes).await?;
+
+            stats
+                .send(Statistic::Progress {
+                    drvs: drv_count,
+                    hashes: hash_count,
+                    total_unique: output.unique.len(),
+                    unique_algos,
+                })
+                .await
+                .unwrap();
+
+            written += 1;
+            if max_chunks == Some(written) {
+                stop.store(true, Ordering::Relaxed);
+            }
+            // Chunks still in flight or waiting to be reordered are dropped
+            // without being checkpointed, so a resumed run picks them up
+            // again.
+            if stop.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn count_drvs(drvs: impl Stream<Item = std::io::Result<Job>>) -> std::io::Result<usize> {
+    smol::pin!(drvs);
+    drvs.try_fold(0, |count, _job| Ok(count + 1)).await
 }
 
 async fn collect_hashes_for_many_derivations(
     drvs: impl IntoIterator<Item = impl AsRef<OsStr>>,
 ) -> std::io::Result<Vec<(String, DerivationHashes)>> {
-    let output = Command::new("nix")
+    let mut proc = Command::new("nix")
         .args(["derivation", "show", "--recursive"])
         .args(drvs)
+        .stdin(Stdio::null())
         .stdout(Stdio::piped())
         .stderr(Stdio::null())
         .kill_on_drop(true)
-        .output()
-        .await?;
-    if !output.status.success() {
-        todo!()
+        .spawn()?;
+    let mut stdout = proc.stdout.take().unwrap();
+
+    let mut splitter = ObjectEntrySplitter::default();
+    let mut drv_hashes = Vec::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = stdout.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        for entry in splitter.feed(&buf[..read]) {
+            drv_hashes.extend(hashes_for_entries(entry.as_bytes()));
+        }
     }
-    let drv_hashes = sonic_rs::to_object_iter(output.stdout.as_slice()).map(|res| {
-        let (drv_path, drv_json) = res.unwrap();
-        (drv_path.to_string(), hashes_for_derivation(&drv_json))
-    });
-    Ok(drv_hashes.collect())
+    check_status(proc.status().await?)?;
+    Ok(drv_hashes)
 }
 
-fn hashes_for_derivation(json: &LazyValue) -> DerivationHashes {
-    static PATHS: LazyLock<PointerTree> = LazyLock::new(|| {
-        let mut paths = PointerTree::new();
-        paths.add_path(&["env", "outputHash"]);
-        paths.add_path(&["env", "outputHashAlgo"]);
-        paths.add_path(&["outputs"]);
-        paths
-    });
-    let values = sonic_rs::get_many(json.as_raw_str(), &PATHS).unwrap();
-    let [env_hash, env_hash_algo, outputs] = values.try_into().unwrap();
+// Queries the whole chunk at once, and each derivation on its own if `nix`
+// fails, so that a bad derivation only loses its own hashes. Derivations
+// that still fail are logged and dropped. Any other error, such as `nix`
+// not being found, is not retried.
+async fn collect_hashes_isolating_failures(
+    drv_paths: &[&str],
+    query: impl AsyncFn(&[&str]) -> std::io::Result<Vec<(String, DerivationHashes)>>,
+) -> std::io::Result<Vec<(String, DerivationHashes)>> {
+    let is_exit_status = |e: &std::io::Error| {
+        e.get_ref()
+            .is_some_and(|inner| inner.is::<ExitStatusError>())
+    };
+    let e = match query(drv_paths).await {
+        Err(e) if is_exit_status(&e) => e,
+        res => return res,
+    };
+    if let [drv_path] = drv_paths {
+        eprintln!("[skip] {drv_path}: {e}");
+        return Ok(Vec::new());
+    }
+    eprintln!(
+        "[retry] chunk of {} derivations failed ({e}), querying each on its own",
+        drv_paths.len()
+    );
 
-    let env_hash = env_hash.as_ref().map(|v| v.as_str().unwrap());
-    let env_hash_algo = env_hash_algo.as_ref().and_then(|v| match v {
-        v if v.is_null() => None,
-        v => Some(v.as_str().unwrap()),
-    });
-    let outputs = outputs.and_then(LazyValue::into_object_iter).unwrap();
+    // Closures overlap, so dependencies shared between the derivations
+    // would otherwise be listed more than once.
+    let mut seen = HashSet::new();
+    let mut drv_hashes = Vec::new();
+    for drv_path in drv_paths {
+        match query(&[drv_path]).await {
+            Ok(hashes) => drv_hashes.extend(
+                hashes
+                    .into_iter()
+                    .filter(|(drv_path, _)| seen.insert(drv_path.clone())),
+            ),
+            Err(e) if is_exit_status(&e) => eprintln!("[skip] {drv_path}: {e}"),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(drv_hashes)
+}
+
+fn hashes_for_entries(json: &[u8]) -> impl Iterator<Item = (String, DerivationHashes)> {
+    sonic_rs::to_object_iter(json).filter_map(|res| {
+        let (drv_path, drv_json) = res
+            .map_err(|e| eprintln!("[skip] nix derivation show output: {e}"))
+            .ok()?;
+        match hashes_for_derivation(&drv_json) {
+            Ok(hashes) => Some((drv_path.to_string(), hashes)),
+            Err(e) => {
+                eprintln!("[skip] {drv_path}: {e}");
+                None
+            }
+        }
+    })
+}
+
+// Splits the single object printed by `nix derivation show` into one-entry
+// objects as its bytes arrive, so that a whole recursive closure is never
+// held in memory at once.
+#[derive(Default)]
+struct ObjectEntrySplitter {
+    buf: Vec<u8>,
+    entry_start: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl ObjectEntrySplitter {
+    fn feed(&mut self, bytes: &[u8]) -> Vec<String> {
+        let mut entries = Vec::new();
+        let scanned = self.buf.len();
+        self.buf.extend_from_slice(bytes);
+        for i in scanned..self.buf.len() {
+            let byte = self.buf[i];
+            if self.in_string {
+                match byte {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => {
+                    self.depth += 1;
+                    if self.depth == 1 {
+                        self.entry_start = i + 1;
+                    }
+                }
+                b'}' | b']' => {
+                    self.depth = self.depth.saturating_sub(1);
+                    if self.depth == 0 {
+                        self.push_entry(i, &mut entries);
+                    }
+                }
+                b',' if self.depth == 1 => {
+                    self.push_entry(i, &mut entries);
+                    self.entry_start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        let consumed = if self.depth == 0 {
+            self.buf.len()
+        } else {
+            self.entry_start
+        };
+        self.buf.drain(..consumed);
+        self.entry_start -= consumed.min(self.entry_start);
+        entries
+    }
+
+    fn push_entry(&self, end: usize, entries: &mut Vec<String>) {
+        let entry = match std::str::from_utf8(&self.buf[self.entry_start..end]) {
+            Ok(entry) => entry.trim(),
+            Err(e) => {
+                eprintln!("[skip] nix derivation show output: {e}");
+                return;
+            }
+        };
+        if !entry.is_empty() {
+            entries.push(format!("{{{entry}}}"));
+        }
+    }
+}
+
+fn hashes_for_derivation(json: &LazyValue) -> Result<DerivationHashes, DrvParseError> {
+    let drv = sonic_rs::from_str::<Derivation>(json.as_raw_str())?;
 
-    let env = env_hash.map(|hash| Hash {
-        hash: hash.to_string(),
-        algo: env_hash_algo.map(str::to_string),
+    let env_hash_algo = drv
+        .env
+        .get("outputHashAlgo")
+        .filter(|algo| !algo.is_empty());
+    let env_hash_mode = match drv.env.get("outputHashMode") {
+        Some(mode) => parse_hash_mode(mode)?,
+        None => HashMode::Flat,
+    };
+    let env = drv.env.get("outputHash").map(|hash| Hash {
+        hash: hash.clone(),
+        algo: env_hash_algo.cloned().or_else(|| sniff_hash_algo(hash)),
+        mode: env_hash_mode,
     });
 
-    let outputs = outputs
-        .map(Result::unwrap)
-        .filter_map(|(out_name, out_json)| {
-            static PATHS: LazyLock<PointerTree> = LazyLock::new(|| {
-                let mut paths = PointerTree::new();
-                paths.add_path(&["hash"]);
-                paths.add_path(&["hashAlgo"]);
-                paths
-            });
-            let values = sonic_rs::get_many(out_json.as_raw_str(), &PATHS).unwrap();
-            let [hash, algo] = values.try_into().unwrap();
+    let mut outputs = Vec::new();
+    for (out_name, output) in drv.outputs {
+        let Some(hash) = output.hash else {
+            continue;
+        };
+        let Some(algo) = output.hash_algo else {
+            return Err(DrvParseError::MissingHashAlgo(out_name));
+        };
+        let mut hash = Hash::with_output_algo(hash, &algo);
+        if let Some(method) = output.method {
+            hash.mode = parse_hash_mode(&method)?;
+        }
+        outputs.push((out_name, hash));
+    }
 
-            let hash = hash.map(|v| v.as_str().unwrap().to_string())?;
-            let algo = algo.map(|v| v.as_str().unwrap().to_string()).unwrap();
+    let inputs = drv.input_drvs.into_keys().collect();
+    Ok(DerivationHashes {
+        env,
+        outputs,
+        inputs,
+    })
+}
 
-            Some((out_name.to_string(), Hash::with_algo(hash, algo)))
-        })
-        .collect();
+fn parse_hash_mode(mode: &str) -> Result<HashMode, DrvParseError> {
+    HashMode::from_output_hash_mode(mode)
+        .ok_or_else(|| DrvParseError::UnknownHashMode(mode.to_string()))
+}
 
-    DerivationHashes { env, outputs }
+// SRI and `algo:`-prefixed hashes carry their algorithm even when
+// `outputHashAlgo` is absent.
+fn sniff_hash_algo(hash: &str) -> Option<String> {
+    let hash = nixapi::hash::Hash::parse(hash).ok()?;
+    Some(hash.algorithm().to_string())
 }
 
 impl Hash {
@@ -355,13 +1337,89 @@ impl Hash {
         Self {
             hash: hash.into(),
             algo: Some(algo.into()),
+            mode: HashMode::Flat,
+        }
+    }
+
+    // Outputs hashed over their NAR serialization declare `r:<algo>` in older
+    // versions of Nix. The prefix is split off so the column parses as a
+    // `HashAlgo`.
+    fn with_output_algo(hash: impl Into<String>, algo: &str) -> Self {
+        let (mode, algo) = match algo.strip_prefix("r:") {
+            Some(algo) => (HashMode::Recursive, algo),
+            None => (HashMode::Flat, algo),
+        };
+        Self {
+            mode,
+            ..Self::with_algo(hash, algo)
         }
     }
 }
 
+impl HashMode {
+    // `nar` is the newer name for `recursive`.
+    fn from_output_hash_mode(mode: &str) -> Option<Self> {
+        match mode {
+            "flat" => Some(Self::Flat),
+            "recursive" | "nar" => Some(Self::Recursive),
+            "text" => Some(Self::Text),
+            "git" => Some(Self::Git),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Flat => "flat",
+            Self::Recursive => "recursive",
+            Self::Text => "text",
+            Self::Git => "git",
+        }
+    }
+}
+
+impl<T> ReorderBuffer<T> {
+    fn new() -> Self {
+        Self {
+            next: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    // Takes the chunk dispatched at `index`, and returns every chunk that is
+    // now ready to be written, in order.
+    fn push(&mut self, index: usize, chunk: T) -> Vec<T> {
+        self.pending.insert(index, chunk);
+        let mut ready = Vec::new();
+        while let Some(chunk) = self.pending.remove(&self.next) {
+            ready.push(chunk);
+            self.next += 1;
+        }
+        ready
+    }
+}
+
+impl Throttle {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: None,
+        }
+    }
+
+    // Returns whether `interval` has passed since it last returned true.
+    fn ready(&mut self, now: Instant) -> bool {
+        if self.last.is_some_and(|last| now - last < self.interval) {
+            return false;
+        }
+        self.last = Some(now);
+        true
+    }
+}
+
 impl<const SCALE: u64> TimingBucket<SCALE> {
     fn new(start: Instant) -> Self {
-        debug_assert!(SCALE > 0 && SCALE % 1000 == 0);
+        debug_assert!(SCALE > 0 && SCALE.is_multiple_of(1000));
         Self {
             last_total: 0,
             last_update: start,
@@ -459,3 +1517,937 @@ impl std::fmt::Display for DisplayElapsed {
         self.0.fmt(f)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::collections::HashSet;
+    use std::ffi::OsString;
+    use std::os::unix::process::ExitStatusExt;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::{Duration, Instant};
+
+    use nixapi::process::ExitStatusError;
+    use smol::channel;
+    use smol::lock::Semaphore;
+    use smol::stream::{self, StreamExt};
+
+    use super::{
+        AlgoCounts, Args, DerivationHashes, DrvParseError, Hash, HashMode, HashOutput, HashRecord,
+        Job, ObjectEntrySplitter, ReorderBuffer, Throttle, UniqueHashes, closure_attrs,
+        collect_hashes_isolating_failures, count_drvs, csv_header, eval_job, for_each_chunk,
+        hashes_for_derivation, hashes_for_entries, open_output, read_complete_lines,
+        read_drv_paths, skip_completed, strip_csv_header, write_chunks,
+    };
+
+    #[test]
+    fn sri_output_hash_without_algo() {
+        let json = r#"{
+            "env": { "outputHash": "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=" },
+            "outputs": { "out": { "path": "/nix/store/00000000000000000000000000000000-empty" } }
+        }"#;
+        let json = sonic_rs::get_from_str(json, std::iter::empty::<&str>()).unwrap();
+        let hashes = hashes_for_derivation(&json).unwrap();
+        let env = hashes.env.unwrap();
+        assert_eq!(env.algo.as_deref(), Some("sha256"));
+        assert!(hashes.outputs.is_empty());
+
+        // Fetchers given an SRI `hash` may leave `outputHashAlgo` empty.
+        let json = r#"{
+            "env": {
+                "outputHash": "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+                "outputHashAlgo": ""
+            },
+            "outputs": { "out": { "path": "/nix/store/00000000000000000000000000000000-empty" } }
+        }"#;
+        let json = sonic_rs::get_from_str(json, std::iter::empty::<&str>()).unwrap();
+        let env = hashes_for_derivation(&json).unwrap().env.unwrap();
+        assert_eq!(env.algo.as_deref(), Some("sha256"));
+    }
+
+    #[test]
+    fn recursive_output_algo() {
+        let json = r#"{
+            "outputs": {
+                "out": { "hash": "aaaa", "hashAlgo": "r:sha256", "path": "/nix/store/a" },
+                "flat": { "hash": "bbbb", "hashAlgo": "sha256", "path": "/nix/store/b" }
+            }
+        }"#;
+        let json = sonic_rs::get_from_str(json, std::iter::empty::<&str>()).unwrap();
+        let hashes = hashes_for_derivation(&json).unwrap();
+        let output = |name: &str| {
+            let (_, hash) = hashes.outputs.iter().find(|(n, _)| n == name).unwrap();
+            hash.clone()
+        };
+        let (out, flat) = (output("out"), output("flat"));
+        assert_eq!(out.algo.as_deref(), Some("sha256"));
+        assert_eq!(out.mode, HashMode::Recursive);
+        assert_eq!(flat.mode, HashMode::Flat);
+        assert!(out.algo.unwrap().parse::<nixapi::hash::HashAlgo>().is_ok());
+
+        let record = HashRecord {
+            hash: Hash::with_output_algo("aaaa", "r:sha256"),
+            origin: None,
+            attr: None,
+        };
+        let csv_record = record.to_csv_record().to_string();
+        assert_eq!(csv_record, r#""aaaa", "sha256", "recursive""#);
+        assert_eq!(HashRecord::from_csv_record(&csv_record), Some(record));
+    }
+
+    #[test]
+    fn output_hash_mode() {
+        let env_mode = |mode: Option<&str>| {
+            let mode = mode.map(|mode| format!(r#", "outputHashMode": "{mode}""#));
+            let json = format!(
+                r#"{{ "env": {{ "outputHash": "aaaa", "outputHashAlgo": "sha256"{} }}, "outputs": {{}} }}"#,
+                mode.unwrap_or_default()
+            );
+            let json = sonic_rs::get_from_str(&json, std::iter::empty::<&str>()).unwrap();
+            hashes_for_derivation(&json).map(|hashes| hashes.env.unwrap().mode)
+        };
+        assert_eq!(env_mode(Some("flat")).unwrap(), HashMode::Flat);
+        assert_eq!(env_mode(Some("recursive")).unwrap(), HashMode::Recursive);
+        assert_eq!(env_mode(Some("nar")).unwrap(), HashMode::Recursive);
+        assert_eq!(env_mode(Some("text")).unwrap(), HashMode::Text);
+        assert_eq!(env_mode(Some("git")).unwrap(), HashMode::Git);
+        assert_eq!(env_mode(None).unwrap(), HashMode::Flat);
+        assert!(matches!(
+            env_mode(Some("nix")),
+            Err(DrvParseError::UnknownHashMode(mode)) if mode == "nix"
+        ));
+
+        let json = r#"{
+            "outputs": { "out": { "hash": "aaaa", "hashAlgo": "sha256", "method": "nar" } }
+        }"#;
+        let json = sonic_rs::get_from_str(json, std::iter::empty::<&str>()).unwrap();
+        let hashes = hashes_for_derivation(&json).unwrap();
+        assert_eq!(hashes.outputs[0].1.mode, HashMode::Recursive);
+
+        for mode in [
+            HashMode::Flat,
+            HashMode::Recursive,
+            HashMode::Text,
+            HashMode::Git,
+        ] {
+            let record = HashRecord {
+                hash: Hash {
+                    mode,
+                    ..Hash::with_algo("aaaa", "sha256")
+                },
+                origin: None,
+                attr: None,
+            };
+            let csv_record = record.to_csv_record().to_string();
+            assert_eq!(HashRecord::from_csv_record(&csv_record), Some(record));
+        }
+    }
+
+    #[test]
+    fn malformed_derivation() {
+        let parse = |json: &str| {
+            let json = sonic_rs::get_from_str(json, std::iter::empty::<&str>()).unwrap();
+            hashes_for_derivation(&json).err()
+        };
+        assert!(matches!(
+            parse(r#"{ "env": { "outputHash": 5 }, "outputs": {} }"#),
+            Some(DrvParseError::Json(_))
+        ));
+        assert!(matches!(
+            parse(r#"{ "env": {} }"#),
+            Some(DrvParseError::Json(_))
+        ));
+        assert!(matches!(
+            parse(r#"{ "outputs": { "out": { "hash": "aaaa" } } }"#),
+            Some(DrvParseError::MissingHashAlgo(output)) if output == "out"
+        ));
+        assert!(parse(r#"{ "outputs": { "out": { "path": "/nix/store/a" } } }"#).is_none());
+    }
+
+    #[test]
+    fn streamed_derivations() {
+        static SHOW: &str = r#"{
+  "/nix/store/a-source.drv": {
+    "env": {
+      "outputHash": "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+      "outputHashAlgo": "sha256",
+      "preferLocalBuild": "1"
+    },
+    "outputs": { "out": { "hash": "aaaa", "hashAlgo": "r:sha256", "path": "/nix/store/a" } }
+  },
+  "/nix/store/b-hello.drv": {
+    "args": ["-c", "echo \"{[,\\\\\" > $out"],
+    "env": { "name": "hello, {world}" },
+    "outputs": { "out": { "path": "/nix/store/b" }, "dev": { "path": "/nix/store/c" } }
+  },
+  "/nix/store/c-broken.drv": { "env": {} }
+}"#;
+        let buffered = hashes_for_entries(SHOW.as_bytes()).collect::<Vec<_>>();
+        assert_eq!(buffered.len(), 2);
+        for chunk_size in [1, 7, 64, SHOW.len()] {
+            let mut splitter = ObjectEntrySplitter::default();
+            let mut streamed = Vec::new();
+            for chunk in SHOW.as_bytes().chunks(chunk_size) {
+                for entry in splitter.feed(chunk) {
+                    streamed.extend(hashes_for_entries(entry.as_bytes()));
+                }
+            }
+            assert_eq!(streamed, buffered, "chunk size {chunk_size}");
+            assert!(splitter.buf.is_empty());
+        }
+        assert!(ObjectEntrySplitter::default().feed(b"{}").is_empty());
+
+        let invalid =
+            b"{\"/nix/store/a.drv\": {\"env\": {\"name\": \"\xff\"}}, \"/nix/store/b.drv\": {}}";
+        assert_eq!(
+            ObjectEntrySplitter::default().feed(invalid),
+            [r#"{"/nix/store/b.drv": {}}"#]
+        );
+    }
+
+    #[test]
+    fn eval_job_lines() {
+        let job = eval_job(
+            r#"{ "attr": "python3Packages.requests", "attrPath": ["python3Packages", "requests"], "drvPath": "/nix/store/a.drv" }"#,
+        );
+        assert_eq!(
+            job.unwrap(),
+            Some((
+                Some("python3Packages.requests".into()),
+                "/nix/store/a.drv".into()
+            ))
+        );
+        let job = eval_job(r#"{ "drvPath": "/nix/store/a.drv" }"#);
+        assert_eq!(job.unwrap(), Some((None, "/nix/store/a.drv".into())));
+        let failed = eval_job(r#"{ "attr": "broken", "error": "oops" }"#);
+        assert_eq!(failed.unwrap(), None);
+        assert!(matches!(
+            eval_job(r#"{ "drvPath": null }"#),
+            Err(DrvParseError::NotAString("drvPath"))
+        ));
+        assert!(matches!(
+            eval_job(r#"{ "attr": 1, "drvPath": "/nix/store/a.drv" }"#),
+            Err(DrvParseError::NotAString("attr"))
+        ));
+        assert!(matches!(
+            eval_job(r#"{ "attr": "hello" }"#),
+            Err(DrvParseError::UnexpectedJob)
+        ));
+    }
+
+    #[test]
+    fn parse_args() {
+        let parse = |args: &[&str]| Args::parse(args.iter().map(OsString::from));
+        assert_eq!(parse(&[]).unwrap(), Args::default());
+        assert!(parse(&["--count-only"]).unwrap().count_only);
+        assert!(parse(&["--bogus"]).is_err());
+        let drvs_from = parse(&["--drvs-from", "-"]).unwrap().drvs_from;
+        assert_eq!(drvs_from.as_deref(), Some("-".as_ref()));
+        assert!(parse(&["--drvs-from"]).is_err());
+        let spill_threshold = parse(&["--spill-to-disk", "1000"]).unwrap().spill_threshold;
+        assert_eq!(spill_threshold, Some(1000));
+        assert!(parse(&["--spill-to-disk", "0"]).is_err());
+        assert!(parse(&["--spill-to-disk", "many"]).is_err());
+        assert!(parse(&["--resume"]).unwrap().resume);
+        assert!(parse(&["--resume", "--spill-to-disk", "10"]).is_err());
+        assert!(parse(&["--with-origin"]).unwrap().with_origin);
+        assert!(parse(&["--header"]).unwrap().header);
+        assert!(parse(&["--ordered"]).unwrap().ordered);
+        let max_chunks = parse(&["--max-chunks", "3"]).unwrap().max_chunks;
+        assert_eq!(max_chunks, Some(3));
+        assert!(parse(&["--max-chunks", "0"]).is_err());
+        assert!(parse(&["--max-chunks"]).is_err());
+    }
+
+    #[test]
+    fn skip_completed_drvs() {
+        let completed = HashSet::from(["/nix/store/b.drv".to_string()]);
+        let drvs = ["/nix/store/a.drv", "/nix/store/b.drv", "/nix/store/c.drv"]
+            .map(|drv_path| Ok((None, drv_path.to_string())));
+        let remaining = smol::block_on(
+            skip_completed(stream::iter(drvs), &completed)
+                .map(|job| job.unwrap().1)
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(remaining, ["/nix/store/a.drv", "/nix/store/c.drv"]);
+    }
+
+    #[test]
+    fn resume_from_partial_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nixpkgs-hashes.csv");
+        let missing = read_complete_lines(&path).unwrap();
+        assert!(missing.is_empty());
+
+        std::fs::write(
+            &path,
+            "\"aaaa\", \"sha256\", \"flat\"\n\"bbbb\", null, \"flat\"\n\"cc",
+        )
+        .unwrap();
+        let records = read_complete_lines(&path).unwrap();
+        assert_eq!(
+            records,
+            [r#""aaaa", "sha256", "flat""#, r#""bbbb", null, "flat""#]
+        );
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            contents,
+            "\"aaaa\", \"sha256\", \"flat\"\n\"bbbb\", null, \"flat\"\n"
+        );
+
+        smol::block_on(async {
+            let mut unique = UniqueHashes::resume(&records, false).unwrap();
+            let seen = HashRecord::from_csv_record(&records[1]).unwrap();
+            assert_eq!(seen.hash.algo, None);
+            assert!(!unique.insert(&seen).await.unwrap());
+            let new = HashRecord {
+                hash: Hash::with_algo("cccc", "sha1"),
+                origin: None,
+                attr: None,
+            };
+            assert!(unique.insert(&new).await.unwrap());
+        });
+    }
+
+    #[test]
+    fn resume_skips_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nixpkgs-hashes.csv");
+        let header = csv_header(true);
+        std::fs::write(
+            &path,
+            format!("{header}\n\"aaaa\", \"sha256\", \"recursive\", \"env\", \"hello\"\n"),
+        )
+        .unwrap();
+
+        let lines = read_complete_lines(&path).unwrap();
+        let records = strip_csv_header(&lines);
+        assert_eq!(
+            records,
+            [r#""aaaa", "sha256", "recursive", "env", "hello""#]
+        );
+        assert!(HashRecord::from_csv_record(header).is_none());
+
+        let unique = UniqueHashes::resume(records, true).unwrap();
+        assert_eq!(unique.len(), 1);
+        assert!(UniqueHashes::resume(records, false).is_err());
+        let plain = [r#""aaaa", "sha256", "flat""#.to_string()];
+        assert_eq!(strip_csv_header(&plain), plain);
+    }
+
+    #[test]
+    fn resume_from_other_columns() {
+        // Written before the mode column, and before it replaced a boolean.
+        for old in [r#""aaaa", "sha256""#, r#""aaaa", "sha256", false"#] {
+            let records = [r#""bbbb", "sha256", "flat""#.to_string(), old.to_string()];
+            let err = UniqueHashes::resume(&records, false).err().unwrap();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            assert!(err.to_string().ends_with(old));
+        }
+    }
+
+    fn write_unique(
+        spill_threshold: Option<usize>,
+        records: &[HashRecord],
+    ) -> (Vec<String>, usize) {
+        smol::block_on(async {
+            let mut unique = UniqueHashes::new(spill_threshold).unwrap();
+            let mut output = Vec::new();
+            for record in records {
+                if unique.insert(record).await.unwrap() {
+                    output.extend_from_slice(record.to_csv_record().to_string().as_bytes());
+                    output.push(b'\n');
+                }
+            }
+            let (total_unique, _) = unique.finish(&mut output).await.unwrap();
+            let records = String::from_utf8(output).unwrap();
+            let mut records = records.lines().map(str::to_string).collect::<Vec<_>>();
+            records.sort();
+            (records, total_unique)
+        })
+    }
+
+    #[test]
+    fn spill_to_disk() {
+        let hashes = (0..50)
+            .map(|i| HashRecord {
+                hash: Hash {
+                    hash: format!("{:04}", i * 7 % 23),
+                    algo: (i % 3 != 0).then(|| ["sha256", "sha1"][i % 2].to_string()),
+                    mode: [HashMode::Flat, HashMode::Recursive][i % 4 / 3],
+                },
+                origin: (i % 5 == 0).then(|| "env".to_string()),
+                attr: None,
+            })
+            .collect::<Vec<_>>();
+        let (in_memory, in_memory_total) = write_unique(None, &hashes);
+        let expected = hashes
+            .iter()
+            .collect::<std::collections::HashSet<_>>()
+            .len();
+        assert_eq!(in_memory.len(), expected);
+        assert_eq!(in_memory_total, expected);
+        for threshold in [1, 2, 5, 100] {
+            let (on_disk, on_disk_total) = write_unique(Some(threshold), &hashes);
+            assert_eq!(on_disk, in_memory);
+            assert_eq!(on_disk_total, expected);
+        }
+    }
+
+    // Counts the allocations made by the current thread while `COUNTING` is
+    // set, so that tests running on other threads don't change the count.
+    struct CountingAllocator;
+
+    thread_local! {
+        static COUNTING: Cell<bool> = const { Cell::new(false) };
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            if COUNTING.try_with(Cell::get).unwrap_or(false) {
+                ALLOCATIONS.with(|count| count.set(count.get() + 1));
+            }
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe { System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    #[test]
+    fn duplicates_are_not_cloned() {
+        let record = |hash: &str| HashRecord {
+            hash: Hash::with_algo(hash, "sha256"),
+            origin: Some("out".into()),
+            attr: None,
+        };
+        let unreadable = record("aaaa");
+        let readable = record("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=");
+        for (record, spill_threshold) in [&unreadable, &readable]
+            .into_iter()
+            .flat_map(|record| [(record, None), (record, Some(100))])
+        {
+            smol::block_on(async {
+                let mut unique = UniqueHashes::new(spill_threshold).unwrap();
+                unique.insert(record).await.unwrap();
+                let before = ALLOCATIONS.get();
+                COUNTING.set(true);
+                for _ in 0..1000 {
+                    assert!(!unique.insert(record).await.unwrap());
+                }
+                COUNTING.set(false);
+                assert_eq!(ALLOCATIONS.get(), before);
+            });
+        }
+    }
+
+    #[test]
+    fn record_origin() {
+        let hash = Hash::with_algo("aaaa", "sha256");
+        let plain = HashRecord {
+            hash: hash.clone(),
+            origin: None,
+            attr: None,
+        };
+        let env = HashRecord {
+            hash: hash.clone(),
+            origin: Some("env".into()),
+            attr: None,
+        };
+        let output = HashRecord {
+            hash,
+            origin: Some("out".into()),
+            attr: Some("hello".into()),
+        };
+        let csv_records = [&plain, &env, &output].map(|r| r.to_csv_record().to_string());
+        assert_eq!(
+            csv_records,
+            [
+                r#""aaaa", "sha256", "flat""#,
+                r#""aaaa", "sha256", "flat", "env", null"#,
+                r#""aaaa", "sha256", "flat", "out", "hello""#,
+            ]
+        );
+        for (record, csv_record) in [plain, env, output].iter().zip(&csv_records) {
+            assert_eq!(
+                HashRecord::from_csv_record(csv_record).as_ref(),
+                Some(record)
+            );
+        }
+        assert_eq!(
+            HashRecord::from_csv_record(r#""aaaa", null, "flat", "out", "x", "y""#),
+            None
+        );
+        assert_eq!(
+            HashRecord::from_csv_record(r#""aaaa", "sha256", "out""#),
+            None
+        );
+        assert_eq!(
+            HashRecord::from_csv_record(r#""aaaa", "sha256", "flat", "out""#),
+            None
+        );
+    }
+
+    #[test]
+    fn quoted_attr() {
+        let record = HashRecord {
+            hash: Hash::with_algo("aaaa", "sha256"),
+            origin: Some("out".into()),
+            attr: Some(r#"nodePackages."@angular/cli", x"#.into()),
+        };
+        let csv_record = record.to_csv_record().to_string();
+        assert_eq!(
+            csv_record,
+            r#""aaaa", "sha256", "flat", "out", "nodePackages.""@angular/cli"", x""#
+        );
+        assert_eq!(HashRecord::from_csv_record(&csv_record), Some(record));
+        assert_eq!(
+            HashRecord::from_csv_record(r#""aaaa", "sha256", "flat", "out", "a"b""#),
+            None
+        );
+        assert_eq!(
+            HashRecord::from_csv_record(r#""aaaa", "sha256", "flat", "out", "a"#),
+            None
+        );
+    }
+
+    #[test]
+    fn dedup_ignores_attr() {
+        let record = |origin: &str, attr: Option<&str>| HashRecord {
+            hash: Hash::with_algo("aaaa", "sha256"),
+            origin: Some(origin.into()),
+            attr: attr.map(str::to_string),
+        };
+        let records = [
+            record("out", Some("hello")),
+            record("out", Some("curl")),
+            record("out", None),
+            record("env", Some("curl")),
+        ];
+        let (in_memory, total) = write_unique(None, &records);
+        assert_eq!(total, 2);
+        assert_eq!(
+            in_memory,
+            [
+                r#""aaaa", "sha256", "flat", "env", "curl""#,
+                r#""aaaa", "sha256", "flat", "out", "hello""#,
+            ]
+        );
+        for threshold in [1, 2, 100] {
+            let (on_disk, total) = write_unique(Some(threshold), &records);
+            assert_eq!(total, 2);
+            assert_eq!(on_disk.len(), 2);
+        }
+    }
+
+    #[test]
+    fn dedup_across_encodings() {
+        let record = |hash: &str, algo: Option<&str>, mode: HashMode| HashRecord {
+            hash: Hash {
+                hash: hash.into(),
+                algo: algo.map(str::to_string),
+                mode,
+            },
+            origin: Some("out".into()),
+            attr: None,
+        };
+        let hex = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let sri = "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+        let records = [
+            record(hex, Some("sha256"), HashMode::Flat),
+            record(sri, Some("sha256"), HashMode::Flat),
+            record(sri, None, HashMode::Flat),
+            record(
+                "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73",
+                Some("sha256"),
+                HashMode::Flat,
+            ),
+            // The same digest over a NAR is another hash.
+            record(hex, Some("sha256"), HashMode::Recursive),
+        ];
+        let (in_memory, total) = write_unique(None, &records);
+        assert_eq!(total, 2);
+        assert_eq!(
+            in_memory,
+            [
+                format!(r#""{hex}", "sha256", "flat", "out", null"#),
+                format!(r#""{hex}", "sha256", "recursive", "out", null"#),
+            ]
+        );
+        for threshold in [1, 2, 100] {
+            let (on_disk, total) = write_unique(Some(threshold), &records);
+            assert_eq!(total, 2);
+            assert_eq!(on_disk.len(), 2);
+        }
+    }
+
+    #[test]
+    fn job_attrs() {
+        let drv = |name: &str, inputs: &[&str]| {
+            let hashes = DerivationHashes {
+                env: None,
+                outputs: Vec::new(),
+                inputs: inputs
+                    .iter()
+                    .map(|input| format!("/nix/store/{input}.drv"))
+                    .collect(),
+            };
+            (format!("/nix/store/{name}.drv"), hashes)
+        };
+        let drv_hashes = [
+            drv("a-source", &[]),
+            drv("b-hello", &["a-source", "c-stdenv"]),
+            drv("c-stdenv", &[]),
+            drv("d-curl", &["c-stdenv", "f-curl-source"]),
+            drv("e-untracked", &[]),
+            drv("f-curl-source", &["c-stdenv"]),
+        ];
+        let batch = [
+            (
+                Some("hello".to_string()),
+                "/nix/store/b-hello.drv".to_string(),
+            ),
+            (
+                Some("curl".to_string()),
+                "/nix/store/d-curl.drv".to_string(),
+            ),
+            (None, "/nix/store/e-untracked.drv".to_string()),
+        ];
+        let attrs = closure_attrs(&batch, &drv_hashes);
+        let attr = |name: &str| attrs.get(&format!("/nix/store/{name}.drv")).copied();
+        assert_eq!(attr("a-source"), Some("hello"));
+        assert_eq!(attr("b-hello"), Some("hello"));
+        assert_eq!(attr("c-stdenv"), Some("hello"));
+        assert_eq!(attr("d-curl"), Some("curl"));
+        assert_eq!(attr("f-curl-source"), Some("curl"));
+        assert_eq!(attr("e-untracked"), None);
+    }
+
+    #[test]
+    fn drvs_from() {
+        let input = b"# derivations to harvest\n\
+            /nix/store/a.drv\n\
+            \n\
+            \x20 /nix/store/b.drv  \n\
+            # /nix/store/skipped.drv\n\
+            /nix/store/c.drv";
+        let mut batches = Vec::<Vec<String>>::new();
+        let collect = async |batch: Vec<Job>| {
+            assert!(batch.iter().all(|(attr, _)| attr.is_none()));
+            batches.push(batch.into_iter().map(|(_, drv_path)| drv_path).collect());
+        };
+        let stop = AtomicBool::new(false);
+        let drvs = read_drv_paths(&input[..]);
+        smol::block_on(for_each_chunk(drvs, 2, &stop, collect)).unwrap();
+        assert_eq!(
+            batches,
+            [
+                vec!["/nix/store/a.drv", "/nix/store/b.drv"],
+                vec!["/nix/store/c.drv"]
+            ]
+        );
+    }
+
+    // Derivations in tests have their own path as their only hash.
+    fn env_hashes<'a>(
+        drv_paths: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<(String, DerivationHashes)> {
+        drv_paths
+            .into_iter()
+            .map(|drv_path| {
+                let hashes = DerivationHashes {
+                    env: Some(Hash::with_algo(drv_path, "sha256")),
+                    outputs: Vec::new(),
+                    inputs: Vec::new(),
+                };
+                (drv_path.to_string(), hashes)
+            })
+            .collect()
+    }
+
+    fn batch_hashes(batch: &[Job]) -> Vec<(String, DerivationHashes)> {
+        env_hashes(batch.iter().map(|(_, drv_path)| drv_path.as_str()))
+    }
+
+    // Returns the output and checkpoint paths along with the output.
+    async fn test_output(dir: &Path) -> (PathBuf, PathBuf, HashOutput) {
+        let output_path = dir.join("nixpkgs-hashes.csv");
+        let checkpoint_path = dir.join("nixpkgs-hashes.ckpt");
+        let output = HashOutput {
+            writer: open_output(&output_path, false).await.unwrap(),
+            checkpoint: open_output(&checkpoint_path, false).await.unwrap(),
+            checkpointing: true,
+            unique: UniqueHashes::new(None).unwrap(),
+            with_origin: false,
+        };
+        (output_path, checkpoint_path, output)
+    }
+
+    #[test]
+    fn stop_after_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let drv_path = |i| format!("/nix/store/{i}.drv");
+        let drvs = stream::iter((0..10).map(|i| Ok((None, drv_path(i)))));
+        let stop = AtomicBool::new(false);
+
+        let (output_path, checkpoint_path) = smol::block_on(async {
+            let (output_path, checkpoint_path, mut output) = test_output(dir.path()).await;
+            let mut chunks = 0;
+            let write = async |batch: Vec<Job>| {
+                let drv_hashes = batch_hashes(&batch);
+                output.write_chunk(&batch, drv_hashes).await.unwrap();
+                chunks += 1;
+                if chunks == 3 {
+                    stop.store(true, Ordering::Relaxed);
+                }
+            };
+            for_each_chunk(drvs, 2, &stop, write).await.unwrap();
+            output.finish().await.unwrap();
+            (output_path, checkpoint_path)
+        });
+
+        let records = read_complete_lines(&output_path).unwrap();
+        let expected = (0..6).map(drv_path).collect::<Vec<_>>();
+        let hashes = records
+            .iter()
+            .map(|record| HashRecord::from_csv_record(record).unwrap().hash.hash)
+            .collect::<Vec<_>>();
+        assert_eq!(hashes, expected);
+        assert_eq!(read_complete_lines(&checkpoint_path).unwrap(), expected);
+    }
+
+    #[test]
+    fn max_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let sem = Arc::new(Semaphore::new(5));
+        let chunk = |index: usize| {
+            let batch = (2 * index..2 * index + 2)
+                .map(|i| (None, format!("/nix/store/{i}.drv")))
+                .collect::<Vec<Job>>();
+            let drv_hashes = batch_hashes(&batch);
+            (index, batch, Ok(drv_hashes), sem.try_acquire_arc().unwrap())
+        };
+        let (chunks_tx, chunks_rx) = channel::unbounded();
+        for index in 0..4 {
+            chunks_tx.try_send(chunk(index)).unwrap();
+        }
+        let (stats_tx, stats_rx) = channel::unbounded();
+        let stop = AtomicBool::new(false);
+
+        let (output_path, checkpoint_path) = smol::block_on(async {
+            let (output_path, checkpoint_path, mut output) = test_output(dir.path()).await;
+            write_chunks(chunks_rx, &mut output, false, Some(2), &stop, &stats_tx)
+                .await
+                .unwrap();
+            output.finish().await.unwrap();
+            (output_path, checkpoint_path)
+        });
+
+        assert!(stop.load(Ordering::Relaxed));
+        assert!(chunks_tx.try_send(chunk(4)).is_err());
+        assert_eq!(stats_rx.len(), 2);
+
+        let expected = (0..4)
+            .map(|i| format!("/nix/store/{i}.drv"))
+            .collect::<Vec<_>>();
+        let hashes = read_complete_lines(&output_path)
+            .unwrap()
+            .iter()
+            .map(|record| HashRecord::from_csv_record(record).unwrap().hash.hash)
+            .collect::<Vec<_>>();
+        assert_eq!(hashes, expected);
+        assert_eq!(read_complete_lines(&checkpoint_path).unwrap(), expected);
+    }
+
+    #[test]
+    fn reorder_buffer() {
+        let mut reorder = ReorderBuffer::new();
+        assert!(reorder.push(1, 'b').is_empty());
+        assert!(reorder.push(3, 'd').is_empty());
+        assert_eq!(reorder.push(0, 'a'), ['a', 'b']);
+        assert_eq!(reorder.push(2, 'c'), ['c', 'd']);
+        assert_eq!(reorder.push(4, 'e'), ['e']);
+    }
+
+    #[test]
+    fn reorder_window() {
+        let sem = Arc::new(Semaphore::new(3));
+        let mut reorder = ReorderBuffer::new();
+        // The chunk at 0 is still being queried.
+        let first = sem.try_acquire_arc().unwrap();
+        assert!(reorder.push(1, sem.try_acquire_arc().unwrap()).is_empty());
+        assert!(reorder.push(2, sem.try_acquire_arc().unwrap()).is_empty());
+        assert!(sem.try_acquire().is_none());
+        let ready = reorder.push(0, first);
+        assert_eq!(ready.len(), 3);
+        drop(ready);
+        assert!(sem.try_acquire().is_some());
+    }
+
+    // Writes ten derivations in chunks of two, as if the chunks had finished
+    // in `arrival` order, and returns the output.
+    fn write_ordered(arrival: [usize; 5]) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let output_path = smol::block_on(async {
+            let (output_path, _, mut output) = test_output(dir.path()).await;
+            let mut reorder = ReorderBuffer::new();
+            for index in arrival {
+                let batch = (2 * index..2 * index + 2)
+                    .map(|i| (None, format!("/nix/store/{i}.drv")))
+                    .collect::<Vec<Job>>();
+                for batch in reorder.push(index, batch) {
+                    let drv_hashes = batch_hashes(&batch);
+                    output.write_chunk(&batch, drv_hashes).await.unwrap();
+                }
+            }
+            output.finish().await.unwrap();
+            output_path
+        });
+        std::fs::read_to_string(output_path).unwrap()
+    }
+
+    #[test]
+    fn ordered_output() {
+        let first = write_ordered([3, 1, 0, 4, 2]);
+        let second = write_ordered([2, 0, 4, 3, 1]);
+        assert_eq!(first, second);
+        let hashes = first
+            .lines()
+            .map(|record| HashRecord::from_csv_record(record).unwrap().hash.hash)
+            .collect::<Vec<_>>();
+        let expected = (0..10)
+            .map(|i| format!("/nix/store/{i}.drv"))
+            .collect::<Vec<_>>();
+        assert_eq!(hashes, expected);
+    }
+
+    // Pretends every derivation depends on a shared one, and that `nix` fails
+    // whenever it is asked about `bad`.
+    async fn query(drv_paths: &[&str]) -> std::io::Result<Vec<(String, DerivationHashes)>> {
+        if drv_paths.contains(&"/nix/store/bad.drv") {
+            let status = std::process::ExitStatus::from_raw(1 << 8);
+            return Err(std::io::Error::other(ExitStatusError(status)));
+        }
+        let shared = "/nix/store/shared.drv";
+        Ok(env_hashes(drv_paths.iter().copied().chain([shared])))
+    }
+
+    #[test]
+    fn isolate_failed_derivation() {
+        let collect = |drv_paths: &[&str]| {
+            let drv_hashes =
+                smol::block_on(collect_hashes_isolating_failures(drv_paths, query)).unwrap();
+            drv_hashes
+                .into_iter()
+                .map(|(drv_path, _)| drv_path)
+                .collect::<Vec<_>>()
+        };
+        let good = ["/nix/store/a.drv", "/nix/store/b.drv"];
+        assert_eq!(
+            collect(&good),
+            [
+                "/nix/store/a.drv",
+                "/nix/store/b.drv",
+                "/nix/store/shared.drv"
+            ]
+        );
+        assert_eq!(
+            collect(&["/nix/store/a.drv", "/nix/store/bad.drv", "/nix/store/b.drv"]),
+            [
+                "/nix/store/a.drv",
+                "/nix/store/shared.drv",
+                "/nix/store/b.drv"
+            ]
+        );
+        assert!(collect(&["/nix/store/bad.drv"]).is_empty());
+
+        let missing = async |_: &[&str]| -> std::io::Result<Vec<(String, DerivationHashes)>> {
+            Err(std::io::ErrorKind::NotFound.into())
+        };
+        let res = smol::block_on(collect_hashes_isolating_failures(&good, missing));
+        assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn count_only() {
+        let drvs = ["/nix/store/a.drv", "/nix/store/b.drv", "/nix/store/c.drv"];
+        let stream = smol::stream::iter(drvs.map(|drv| Ok((None, drv.to_string()))));
+        assert_eq!(smol::block_on(count_drvs(stream)).unwrap(), 3);
+
+        let failing = smol::stream::iter([
+            Ok((None, "/nix/store/a.drv".to_string())),
+            Err(std::io::Error::other("nix-eval-jobs failed")),
+        ]);
+        assert!(smol::block_on(count_drvs(failing)).is_err());
+    }
+
+    // Checks against the `nix-eval-jobs` on `PATH`, when there is one. The
+    // derivations are only instantiated, so their system doesn't matter.
+    #[cfg(feature = "nix-cli")]
+    #[test]
+    fn nix_cli_count_only() {
+        use std::io::ErrorKind;
+
+        use super::nix_eval_jobs;
+
+        const EXPR: &str = r#"
+          let
+            drv = name: derivation {
+              inherit name;
+              system = "x86_64-linux";
+              builder = "/bin/sh";
+            };
+          in {
+            a = drv "a";
+            b = drv "b";
+            nested = { c = drv "c"; };
+          }
+        "#;
+        let count = smol::block_on(async {
+            let jobs = match nix_eval_jobs(true, EXPR).await {
+                Ok(jobs) => jobs,
+                Err(e) if e.kind() == ErrorKind::NotFound => return None,
+                Err(e) => panic!("failed to run `nix-eval-jobs`: {e}"),
+            };
+            Some(count_drvs(jobs).await.unwrap())
+        });
+        match count {
+            Some(count) => assert_eq!(count, 3),
+            None => eprintln!("skipping, `nix-eval-jobs` is not on PATH"),
+        }
+    }
+
+    #[test]
+    fn throttle() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut throttle = Throttle::new(Duration::from_secs(1));
+        let ready =
+            [0, 10, 999, 1000, 1500, 2001, 2002, 5000].map(|millis| throttle.ready(at(millis)));
+        assert_eq!(ready, [true, false, false, true, false, true, false, true]);
+    }
+
+    #[test]
+    fn algo_counts() {
+        let mut first = AlgoCounts::default();
+        for algo in ["sha256", "sha1", "sha256", "md5"] {
+            first.add(Some(algo));
+        }
+        let mut second = AlgoCounts::default();
+        second.add(Some("sha256"));
+        second.add(None);
+
+        let mut total = AlgoCounts::default();
+        total.merge(&first);
+        total.merge(&second);
+        assert_eq!(total.to_string(), "sha256: 3, md5: 1, null: 1, sha1: 1");
+    }
+}
@@ -0,0 +1,233 @@
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::hash::{Hash, HashAlgo};
+use crate::store_path::FileIngestionMethod;
+
+// A derivation as printed by `nix derivation show`, which maps each `.drv`
+// path to one of these.
+// <https://github.com/NixOS/nix/blob/c9211b0b2d52a26ed666780b763b39a5bddd3fb3/src/libstore/derivations.cc>
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Derivation {
+    #[serde(default)]
+    pub name: String,
+    pub outputs: IndexMap<String, DerivationOutput>,
+    #[serde(default)]
+    pub input_drvs: IndexMap<String, InputDrv>,
+    #[serde(default)]
+    pub input_srcs: Vec<String>,
+    #[serde(default)]
+    pub system: String,
+    #[serde(default)]
+    pub builder: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: IndexMap<String, String>,
+}
+
+// Fixed outputs have a `hash`, and either a `method` or an `r:`-prefixed
+// `hashAlgo` depending on the version of Nix. Content-addressed outputs have
+// no `path` until they are built.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DerivationOutput {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash_algo: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InputDrv {
+    pub outputs: Vec<String>,
+    #[serde(default)]
+    pub dynamic_outputs: IndexMap<String, serde_json::Value>,
+}
+
+impl Derivation {
+    // Returns `None` for derivations that aren't fixed-output, and for ones
+    // whose hash doesn't parse or that use a mode other than `flat`,
+    // `recursive` or its newer name `nar`.
+    pub fn fixed_output_hash(&self) -> Option<(Hash, FileIngestionMethod)> {
+        let hash = self.env.get("outputHash")?;
+        let hash = match self.env.get("outputHashAlgo").map(String::as_str) {
+            None | Some("") => Hash::parse(hash).ok()?,
+            Some(algo) => Hash::parse_as(hash, algo.parse::<HashAlgo>().ok()?).ok()?,
+        };
+        let method = match self.env.get("outputHashMode").map(String::as_str) {
+            None | Some("flat") => FileIngestionMethod::Flat,
+            Some("recursive" | "nar") => FileIngestionMethod::Recursive,
+            Some(_) => return None,
+        };
+        Some((hash, method))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::IndexMap;
+    use serde_json::json;
+    use test_case::test_case;
+
+    use super::{Derivation, DerivationOutput};
+    use crate::hash::{Hash, HashAlgo};
+    use crate::store_path::FileIngestionMethod;
+
+    static SHOW: &str = r#"{
+  "/nix/store/8gsd5b8ra0cqbbyfzhpkv5i08spvqr7k-hello-2.12.1.tar.gz.drv": {
+    "args": [
+      "-e",
+      "/nix/store/v6x3cs394jgqfbi0a42pam708flxaphh-default-builder.sh"
+    ],
+    "builder": "/nix/store/4bj2kxdm1462fzcc2i2s4dn33g2angcc-bash-5.2p32/bin/bash",
+    "env": {
+      "out": "/nix/store/pa10z4ngm0g83kx9mssrqzz30s84vq7k-hello-2.12.1.tar.gz",
+      "outputHash": "sha256-jZkUKv2SV28wsM18tCqNxoCZmLxdYH2Idh9RLibH2yA=",
+      "outputHashAlgo": "",
+      "outputHashMode": "flat",
+      "urls": "mirror://gnu/hello/hello-2.12.1.tar.gz"
+    },
+    "inputDrvs": {
+      "/nix/store/1fwyq4smzsn1fjlk3n1xjxqzw5k4cdz1-curl-8.9.1.drv": {
+        "dynamicOutputs": {},
+        "outputs": [
+          "dev"
+        ]
+      }
+    },
+    "inputSrcs": [
+      "/nix/store/v6x3cs394jgqfbi0a42pam708flxaphh-default-builder.sh"
+    ],
+    "name": "hello-2.12.1.tar.gz",
+    "outputs": {
+      "out": {
+        "hash": "8d99142afd92576f30b0cd7cb42a8dc6809998bc5d607d88761f512e26c7db20",
+        "hashAlgo": "sha256",
+        "method": "flat",
+        "path": "/nix/store/pa10z4ngm0g83kx9mssrqzz30s84vq7k-hello-2.12.1.tar.gz"
+      }
+    },
+    "system": "builtin"
+  },
+  "/nix/store/gx2ynz3ql2w0bfrv4vv4wbzxl2hy7pvz-hello-2.12.1.drv": {
+    "args": [
+      "-e",
+      "/nix/store/v6x3cs394jgqfbi0a42pam708flxaphh-default-builder.sh"
+    ],
+    "builder": "/nix/store/4bj2kxdm1462fzcc2i2s4dn33g2angcc-bash-5.2p32/bin/bash",
+    "env": {
+      "name": "hello-2.12.1",
+      "out": "/nix/store/1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-hello-2.12.1",
+      "src": "/nix/store/pa10z4ngm0g83kx9mssrqzz30s84vq7k-hello-2.12.1.tar.gz",
+      "system": "x86_64-linux"
+    },
+    "inputDrvs": {
+      "/nix/store/8gsd5b8ra0cqbbyfzhpkv5i08spvqr7k-hello-2.12.1.tar.gz.drv": {
+        "dynamicOutputs": {},
+        "outputs": [
+          "out"
+        ]
+      }
+    },
+    "inputSrcs": [
+      "/nix/store/v6x3cs394jgqfbi0a42pam708flxaphh-default-builder.sh"
+    ],
+    "name": "hello-2.12.1",
+    "outputs": {
+      "out": {
+        "path": "/nix/store/1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-hello-2.12.1"
+      }
+    },
+    "system": "x86_64-linux"
+  }
+}"#;
+
+    fn show() -> IndexMap<String, Derivation> {
+        serde_json::from_str(SHOW).unwrap()
+    }
+
+    #[test]
+    fn fixed_output() {
+        let drvs = show();
+        let src = &drvs["/nix/store/8gsd5b8ra0cqbbyfzhpkv5i08spvqr7k-hello-2.12.1.tar.gz.drv"];
+        assert_eq!(src.name, "hello-2.12.1.tar.gz");
+        assert_eq!(src.system, "builtin");
+        assert_eq!(src.env["outputHashMode"], "flat");
+        let out = &src.outputs["out"];
+        assert_eq!(out.hash_algo.as_deref(), Some("sha256"));
+        assert_eq!(out.method.as_deref(), Some("flat"));
+        assert!(out.hash.is_some());
+        let curl = &src.input_drvs["/nix/store/1fwyq4smzsn1fjlk3n1xjxqzw5k4cdz1-curl-8.9.1.drv"];
+        assert_eq!(curl.outputs, ["dev"]);
+    }
+
+    #[test]
+    fn input_addressed() {
+        let drvs = show();
+        let hello = &drvs["/nix/store/gx2ynz3ql2w0bfrv4vv4wbzxl2hy7pvz-hello-2.12.1.drv"];
+        assert_eq!(hello.system, "x86_64-linux");
+        assert_eq!(hello.args[0], "-e");
+        assert_eq!(hello.input_srcs.len(), 1);
+        assert!(hello.builder.ends_with("/bin/bash"));
+        assert_eq!(
+            hello.outputs["out"],
+            DerivationOutput {
+                path: Some("/nix/store/1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-hello-2.12.1".into()),
+                ..Default::default()
+            }
+        );
+
+        let value = serde_json::to_value(&drvs).unwrap();
+        assert_eq!(
+            value,
+            serde_json::from_str::<serde_json::Value>(SHOW).unwrap()
+        );
+    }
+
+    #[test]
+    fn legacy_recursive_output() {
+        let json = r#"{
+            "outputs": { "out": { "hash": "aaaa", "hashAlgo": "r:sha256", "path": "/nix/store/a" } }
+        }"#;
+        let drv = serde_json::from_str::<Derivation>(json).unwrap();
+        assert_eq!(drv.outputs["out"].hash_algo.as_deref(), Some("r:sha256"));
+        assert_eq!(drv.outputs["out"].method, None);
+        assert!(drv.env.is_empty() && drv.input_drvs.is_empty());
+    }
+
+    #[test]
+    fn fixed_output_hash_flat() {
+        let drvs = show();
+        let src = &drvs["/nix/store/8gsd5b8ra0cqbbyfzhpkv5i08spvqr7k-hello-2.12.1.tar.gz.drv"];
+        let (hash, method) = src.fixed_output_hash().unwrap();
+        assert_eq!(method, FileIngestionMethod::Flat);
+        assert!(hash.matches_input(src.outputs["out"].hash.as_deref().unwrap()));
+
+        let hello = &drvs["/nix/store/gx2ynz3ql2w0bfrv4vv4wbzxl2hy7pvz-hello-2.12.1.drv"];
+        assert_eq!(hello.fixed_output_hash(), None);
+    }
+
+    #[test_case(Some("recursive") => Some(FileIngestionMethod::Recursive))]
+    #[test_case(Some("nar") => Some(FileIngestionMethod::Recursive))]
+    #[test_case(Some("flat") => Some(FileIngestionMethod::Flat))]
+    #[test_case(None => Some(FileIngestionMethod::Flat))]
+    #[test_case(Some("text") => None)]
+    fn fixed_output_hash_mode(mode: Option<&str>) -> Option<FileIngestionMethod> {
+        let empty = "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73";
+        let mut env = json!({ "outputHash": empty, "outputHashAlgo": "sha256" });
+        if let Some(mode) = mode {
+            env["outputHashMode"] = mode.into();
+        }
+        let drv = serde_json::from_value::<Derivation>(json!({ "env": env, "outputs": {} }));
+        let (hash, method) = drv.unwrap().fixed_output_hash()?;
+        assert_eq!(hash, Hash::compute(HashAlgo::Sha256, b""));
+        Some(method)
+    }
+}
@@ -1,7 +1,10 @@
+use std::ffi::OsString;
+use std::time::SystemTime;
+
 use serde::{Deserialize, Serialize};
 use url::Url;
 
-use super::PublicKey;
+use super::{PublicKey, system_time_from_unix};
 
 // <https://github.com/NixOS/nix/blob/c9211b0b2d52a26ed666780b763b39a5bddd3fb3/src/libfetchers/git.cc#L202-L219>
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -38,12 +41,321 @@ pub struct GitInputScheme {
     pub verify_commit: bool,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub keytype: Option<String>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub public_key: Option<PublicKey>,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub public_keys: Vec<PublicKey>,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum GitInputError {
+    #[error("`verifyCommit` is set, but no `publicKey` or `publicKeys` are given")]
+    MissingPublicKeys,
+}
+
 fn is_false(flag: &bool) -> bool {
     !flag
 }
+
+impl GitInputScheme {
+    // A scheme with only `url` set, as if every other attribute were left out.
+    pub fn new(url: Url) -> Self {
+        Self {
+            url,
+            r#ref: None,
+            rev: None,
+            shallow: false,
+            submodules: false,
+            lfs: false,
+            export_ignore: false,
+            last_modified: None,
+            rev_count: None,
+            nar_hash: None,
+            all_refs: false,
+            name: None,
+            dirty_rev: None,
+            dirty_short_rev: None,
+            verify_commit: false,
+            keytype: None,
+            public_key: None,
+            public_keys: Vec::new(),
+        }
+    }
+
+    pub fn with_ref(self, r#ref: impl Into<String>) -> Self {
+        Self {
+            r#ref: Some(r#ref.into()),
+            ..self
+        }
+    }
+
+    pub fn with_rev(self, rev: impl Into<String>) -> Self {
+        Self {
+            rev: Some(rev.into()),
+            ..self
+        }
+    }
+
+    pub fn effective_ref(&self) -> &str {
+        self.r#ref.as_deref().unwrap_or("HEAD")
+    }
+
+    // Falls back to `source`, the name Nix gives inputs it can't name.
+    pub fn effective_name(&self) -> String {
+        if let Some(name) = &self.name {
+            return name.clone();
+        }
+        let segment = self
+            .url
+            .path_segments()
+            .and_then(|mut segments| segments.rfind(|segment| !segment.is_empty()))
+            .unwrap_or("");
+        match segment.strip_suffix(".git").unwrap_or(segment) {
+            "" => "source".to_string(),
+            name => name.to_string(),
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.rev.is_some() && self.nar_hash.is_some()
+    }
+
+    // Everything Nix records when it locks a git input. Shallow clones don't
+    // know their history, so Nix leaves out `revCount` for them.
+    // <https://github.com/NixOS/nix/blob/c9211b0b2d52a26ed666780b763b39a5bddd3fb3/src/libfetchers/git.cc>
+    pub fn is_fully_locked(&self) -> bool {
+        self.is_locked()
+            && self.last_modified.is_some()
+            && (self.rev_count.is_some() || self.shallow)
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty_rev.is_some()
+    }
+
+    // A dirty working tree has no `rev`, only `dirtyRev` (`<rev>-dirty`).
+    pub fn effective_rev(&self) -> Option<&str> {
+        self.dirty_rev.as_deref().or(self.rev.as_deref())
+    }
+
+    pub fn validate(&self) -> Result<(), GitInputError> {
+        if self.verify_commit && self.public_key.is_none() && self.public_keys.is_empty() {
+            return Err(GitInputError::MissingPublicKeys);
+        }
+        Ok(())
+    }
+
+    pub fn modified_time(&self) -> Option<SystemTime> {
+        self.last_modified.map(system_time_from_unix)
+    }
+
+    // The `git+<url>` flake reference that `nix` accepts as an installable.
+    // The attributes that select what is fetched go in the query, sorted by
+    // name like Nix prints them. Ones that only a lock records, such as
+    // `narHash`, are left out, as are the commit verification ones.
+    // <https://github.com/NixOS/nix/blob/c9211b0b2d52a26ed666780b763b39a5bddd3fb3/src/libfetchers/git.cc>
+    pub fn to_cli_arg(&self) -> OsString {
+        // `Url::set_scheme` refuses to turn a special scheme like `https`
+        // into a non-special one like `git+https`.
+        let mut url = match self.url.scheme() {
+            "git" => self.url.clone(),
+            _ => Url::parse(&format!("git+{}", self.url)).unwrap(),
+        };
+        let flag = |set: bool| set.then_some("1");
+        let params = [
+            ("exportIgnore", flag(self.export_ignore)),
+            ("lfs", flag(self.lfs)),
+            ("ref", self.r#ref.as_deref()),
+            ("rev", self.rev.as_deref()),
+            ("shallow", flag(self.shallow)),
+            ("submodules", flag(self.submodules)),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?)))
+        .collect::<Vec<_>>();
+        if !params.is_empty() {
+            url.query_pairs_mut().extend_pairs(params);
+        }
+        url.to_string().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use serde_json::json;
+    use test_case::test_case;
+
+    use super::{GitInputError, GitInputScheme};
+
+    fn scheme(value: serde_json::Value) -> GitInputScheme {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn new() {
+        let url = "https://example.com/repo.git";
+        let rev = "0123456789abcdef0123456789abcdef01234567";
+        let bare = GitInputScheme::new(url.parse().unwrap());
+        assert_eq!(bare, scheme(json!({ "url": url })));
+        assert_eq!(
+            serde_json::to_value(&bare).unwrap(),
+            json!({ "url": url, "allRefs": false })
+        );
+
+        let pinned = bare.with_ref("main").with_rev(rev);
+        assert_eq!(
+            pinned,
+            scheme(json!({ "url": url, "ref": "main", "rev": rev }))
+        );
+    }
+
+    #[test]
+    fn effective_ref() {
+        let unset = scheme(json!({ "url": "https://example.com/repo.git" }));
+        assert_eq!(unset.effective_ref(), "HEAD");
+        let set = scheme(json!({ "url": "https://example.com/repo.git", "ref": "main" }));
+        assert_eq!(set.effective_ref(), "main");
+    }
+
+    #[test_case("https://github.com/foo/bar.git", "bar" ; "dot git")]
+    #[test_case("https://github.com/foo/bar/", "bar" ; "trailing slash")]
+    #[test_case("https://github.com/foo/bar.git/", "bar" ; "dot git and trailing slash")]
+    #[test_case("file:///home/user/repo", "repo" ; "file url")]
+    #[test_case("https://example.com", "source" ; "no path")]
+    #[test_case("https://example.com/.git", "source" ; "bare dot git")]
+    fn effective_name(url: &str, expect: &str) {
+        assert_eq!(scheme(json!({ "url": url })).effective_name(), expect);
+        let named = scheme(json!({ "url": url, "name": "custom" }));
+        assert_eq!(named.effective_name(), "custom");
+    }
+
+    #[test]
+    fn is_locked() {
+        let rev = "0123456789abcdef0123456789abcdef01234567";
+        let nar_hash = "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+        let url = "https://example.com/repo.git";
+        assert!(!scheme(json!({ "url": url })).is_locked());
+        assert!(!scheme(json!({ "url": url, "rev": rev })).is_locked());
+        assert!(!scheme(json!({ "url": url, "narHash": nar_hash })).is_locked());
+        assert!(scheme(json!({ "url": url, "rev": rev, "narHash": nar_hash })).is_locked());
+    }
+
+    #[test_case(json!({}) => true ; "complete")]
+    #[test_case(json!({ "rev": null }) => false ; "no rev")]
+    #[test_case(json!({ "narHash": null }) => false ; "no nar hash")]
+    #[test_case(json!({ "lastModified": null }) => false ; "no last modified")]
+    #[test_case(json!({ "revCount": null }) => false ; "no rev count")]
+    #[test_case(json!({ "revCount": null, "shallow": true }) => true ; "shallow")]
+    fn is_fully_locked(overrides: serde_json::Value) -> bool {
+        let mut value = json!({
+            "url": "https://example.com/repo.git",
+            "rev": "0123456789abcdef0123456789abcdef01234567",
+            "narHash": "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+            "lastModified": 1700000000,
+            "revCount": 412,
+        });
+        for (key, field) in overrides.as_object().unwrap() {
+            value[key] = field.clone();
+        }
+        scheme(value).is_fully_locked()
+    }
+
+    #[test]
+    fn dirty_rev() {
+        let rev = "0123456789abcdef0123456789abcdef01234567";
+        let url = "https://example.com/repo.git";
+        let clean = scheme(json!({ "url": url, "rev": rev }));
+        assert!(!clean.is_dirty());
+        assert_eq!(clean.effective_rev(), Some(rev));
+
+        let dirty_rev = format!("{rev}-dirty");
+        let dirty = scheme(json!({
+            "url": url,
+            "dirtyRev": dirty_rev,
+            "dirtyShortRev": "0123456-dirty",
+        }));
+        assert!(dirty.is_dirty());
+        assert_eq!(dirty.effective_rev(), Some(dirty_rev.as_str()));
+        assert_eq!(scheme(json!({ "url": url })).effective_rev(), None);
+    }
+
+    #[test]
+    fn modified_time() {
+        let url = "https://example.com/repo.git";
+        assert_eq!(scheme(json!({ "url": url })).modified_time(), None);
+        assert_eq!(
+            scheme(json!({ "url": url, "lastModified": 1700000000 })).modified_time(),
+            Some(UNIX_EPOCH + Duration::from_secs(1700000000))
+        );
+        assert_eq!(
+            scheme(json!({ "url": url, "lastModified": -86400 })).modified_time(),
+            Some(UNIX_EPOCH - Duration::from_secs(86400))
+        );
+    }
+
+    #[test_case(
+        json!({ "url": "https://example.com/repo.git" })
+        => "git+https://example.com/repo.git"
+        ; "plain"
+    )]
+    #[test_case(
+        json!({
+            "url": "https://example.com/repo.git",
+            "rev": "0123456789abcdef0123456789abcdef01234567",
+            "submodules": true,
+        })
+        => "git+https://example.com/repo.git?rev=0123456789abcdef0123456789abcdef01234567&submodules=1"
+        ; "submodules and rev"
+    )]
+    #[test_case(
+        json!({
+            "url": "ssh://git@example.com/repo.git",
+            "ref": "refs/heads/main",
+            "shallow": true,
+            "lfs": true,
+            "exportIgnore": true,
+            "narHash": "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+        })
+        => "git+ssh://git@example.com/repo.git?exportIgnore=1&lfs=1&ref=refs%2Fheads%2Fmain&shallow=1"
+        ; "flags"
+    )]
+    #[test_case(
+        json!({ "url": "file:///home/user/repo", "ref": "main" })
+        => "git+file:///home/user/repo?ref=main"
+        ; "file"
+    )]
+    #[test_case(
+        json!({ "url": "git://example.com/repo.git?dir=sub", "ref": "main" })
+        => "git://example.com/repo.git?dir=sub&ref=main"
+        ; "git scheme with query"
+    )]
+    fn to_cli_arg(value: serde_json::Value) -> String {
+        scheme(value).to_cli_arg().into_string().unwrap()
+    }
+
+    #[test]
+    fn verification_fields_omitted() {
+        let unverified = scheme(json!({ "url": "https://example.com/repo.git" }));
+        let value = serde_json::to_value(&unverified).unwrap();
+        for field in ["verifyCommit", "keytype", "publicKey", "publicKeys"] {
+            assert!(value.get(field).is_none(), "`{field}` was serialized");
+        }
+    }
+
+    #[test]
+    fn validate() {
+        let url = "https://example.com/repo.git";
+        let key = json!({ "type": "ssh-ed25519", "key": "AAAA" });
+        assert_eq!(scheme(json!({ "url": url })).validate(), Ok(()));
+        assert_eq!(
+            scheme(json!({ "url": url, "verifyCommit": true })).validate(),
+            Err(GitInputError::MissingPublicKeys)
+        );
+        let single = scheme(json!({ "url": url, "verifyCommit": true, "publicKey": key }));
+        assert_eq!(single.validate(), Ok(()));
+        let many = scheme(json!({ "url": url, "verifyCommit": true, "publicKeys": [key] }));
+        assert_eq!(many.validate(), Ok(()));
+    }
+}
@@ -1,5 +1,8 @@
 pub mod git;
 
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use serde::{Deserialize, Serialize};
 
 // <https://github.com/NixOS/nix/blob/c9211b0b2d52a26ed666780b763b39a5bddd3fb3/src/libfetchers/include/nix/fetchers/fetchers.hh#L274>
@@ -8,3 +11,74 @@ pub struct PublicKey {
     pub r#type: String,
     pub key: String,
 }
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum PublicKeyParseError {
+    #[error("expected a `<type> <key>` line")]
+    MissingKey,
+}
+
+impl PublicKey {
+    pub fn to_openssh(&self) -> String {
+        format!("{} {}", self.r#type, self.key)
+    }
+}
+
+// Accepts a line from an OpenSSH `.pub` file, dropping the trailing comment.
+impl FromStr for PublicKey {
+    type Err = PublicKeyParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some(r#type), Some(key)) => Ok(Self {
+                r#type: r#type.to_string(),
+                key: key.to_string(),
+            }),
+            _ => Err(PublicKeyParseError::MissingKey),
+        }
+    }
+}
+
+pub(crate) fn system_time_from_unix(secs: i64) -> SystemTime {
+    let offset = Duration::from_secs(secs.unsigned_abs());
+    if secs < 0 {
+        UNIX_EPOCH - offset
+    } else {
+        UNIX_EPOCH + offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{PublicKey, PublicKeyParseError};
+
+    static KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl";
+
+    #[test]
+    fn openssh_roundtrip() {
+        let json = json!({ "type": "ssh-ed25519", "key": KEY });
+        let key = serde_json::from_value::<PublicKey>(json.clone()).unwrap();
+        let line = key.to_openssh();
+        assert_eq!(line, format!("ssh-ed25519 {KEY}"));
+        assert_eq!(line.parse::<PublicKey>(), Ok(key.clone()));
+        assert_eq!(serde_json::to_value(&key).unwrap(), json);
+
+        let pub_file = format!("ssh-ed25519 {KEY} user@host\n");
+        assert_eq!(pub_file.parse::<PublicKey>(), Ok(key));
+    }
+
+    #[test]
+    fn openssh_missing_key() {
+        assert_eq!(
+            "ssh-ed25519".parse::<PublicKey>(),
+            Err(PublicKeyParseError::MissingKey)
+        );
+        assert_eq!(
+            "".parse::<PublicKey>(),
+            Err(PublicKeyParseError::MissingKey)
+        );
+    }
+}
@@ -1,8 +1,13 @@
 use std::cell::{Ref, RefCell, RefMut};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::time::SystemTime;
 
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 
+use crate::fetchers::system_time_from_unix;
+use crate::hash::{Hash, HashAlgo, ParseError};
+
 pub const MAX_SUPPORTED_LOCK_VERSION: u32 = 7;
 pub const MIN_SUPPORTED_LOCK_VERSION: u32 = 5;
 
@@ -10,6 +15,10 @@ fn default_true() -> bool {
     true
 }
 
+fn is_true(flag: &bool) -> bool {
+    *flag
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct LockFile {
@@ -18,6 +27,39 @@ pub struct LockFile {
     version: u32,
 }
 
+#[derive(Clone, Debug, PartialEq, thiserror::Error)]
+pub enum LockError {
+    #[error("root node has no input named `{0}`")]
+    MissingInput(String),
+    #[error("root node already has an input named `{0}`")]
+    InputExists(String),
+    #[error("input `{0}` does not resolve to a node in the lock file")]
+    Unresolved(String),
+    #[error("node `{0}` is not locked")]
+    NotLocked(String),
+    #[error("locked input does not specify a `type`")]
+    MissingType,
+    #[error("locked input of type `{input_type}` is missing `{attr}`")]
+    MissingAttr {
+        input_type: String,
+        attr: &'static str,
+    },
+    #[error("indirect inputs cannot be locked")]
+    Indirect,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MigrationError {
+    #[error("lock file does not specify a `version`")]
+    MissingVersion,
+    #[error(
+        "lock file version {0} is not supported, expected {MIN_SUPPORTED_LOCK_VERSION} to {MAX_SUPPORTED_LOCK_VERSION}"
+    )]
+    UnsupportedVersion(u64),
+    #[error("lock file is malformed: {0}")]
+    Malformed(#[from] serde_json::Error),
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, untagged)]
 pub enum NodeEdge {
@@ -35,20 +77,41 @@ pub enum Node {
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct LockedNode {
-    #[serde(skip_serializing_if = "Clone::clone", default = "default_true")]
+    #[serde(skip_serializing_if = "is_true", default = "default_true")]
     flake: bool,
     #[serde(skip_serializing_if = "IndexMap::is_empty", default)]
     inputs: IndexMap<String, RefCell<NodeEdge>>,
     locked: serde_json::Value,
     original: serde_json::Value,
+    // The input path of the flake that a relative `path:` input is relative to.
+    // <https://github.com/NixOS/nix/blob/c9211b0b2d52a26ed666780b763b39a5bddd3fb3/src/libflake/lockfile.cc>
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    parent: Option<Vec<String>>,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Default)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct UnlockedNode {
+    // Nix omits `inputs` when there are none, which leaves the root of a flake
+    // without inputs as an empty object.
+    #[serde(skip_serializing_if = "IndexMap::is_empty", default)]
     inputs: IndexMap<String, RefCell<NodeEdge>>,
 }
 
+// Serializes the keys of every object in sorted order. `serde_json::Map` is
+// only sorted while its `preserve_order` feature is off, which any crate in
+// the dependency graph can turn on.
+struct SortedKeys<'a>(&'a serde_json::Value);
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LockSummary {
+    pub nodes: usize,
+    // Keyed by `locked.type`; nodes without one are only counted in `nodes`.
+    pub by_type: BTreeMap<String, usize>,
+    pub follows: usize,
+    pub unlocked: usize,
+}
+
 impl NodeEdge {
     pub fn index(&self) -> Option<&str> {
         match self {
@@ -126,6 +189,50 @@ where
     }
 }
 
+impl LockedNode {
+    pub fn modified_time(&self) -> Option<SystemTime> {
+        self.locked["lastModified"]
+            .as_i64()
+            .map(system_time_from_unix)
+    }
+
+    pub fn validate_as_locked(&self) -> Result<(), LockError> {
+        let input_type = self.locked["type"].as_str().ok_or(LockError::MissingType)?;
+        let required = match input_type {
+            "indirect" => return Err(LockError::Indirect),
+            "github" | "gitlab" | "sourcehut" | "git" | "hg" => "rev",
+            "tarball" | "file" => "narHash",
+            _ => return Ok(()),
+        };
+        if self.locked.get(required).is_some_and(|v| !v.is_null()) {
+            Ok(())
+        } else {
+            Err(LockError::MissingAttr {
+                input_type: input_type.into(),
+                attr: required,
+            })
+        }
+    }
+}
+
+impl Serialize for SortedKeys<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            serde_json::Value::Array(items) => serializer.collect_seq(items.iter().map(SortedKeys)),
+            serde_json::Value::Object(map) => {
+                let mut entries = map.iter().collect::<Vec<_>>();
+                entries.sort_unstable_by_key(|(key, _)| *key);
+                serializer.collect_map(
+                    entries
+                        .into_iter()
+                        .map(|(key, value)| (key, SortedKeys(value))),
+                )
+            }
+            value => value.serialize(serializer),
+        }
+    }
+}
+
 impl Node {
     fn edges(&self) -> &IndexMap<String, RefCell<NodeEdge>> {
         match self {
@@ -134,6 +241,13 @@ impl Node {
         }
     }
 
+    fn edges_mut(&mut self) -> &mut IndexMap<String, RefCell<NodeEdge>> {
+        match self {
+            Self::Locked(LockedNode { inputs, .. }) => inputs,
+            Self::Unlocked(UnlockedNode { inputs }) => inputs,
+        }
+    }
+
     pub fn iter_edges(&self) -> impl Iterator<Item = (&str, Ref<'_, NodeEdge>)> {
         self.edges()
             .iter()
@@ -172,10 +286,63 @@ impl LockFile {
         }
     }
 
+    // Relabels a lock file of any supported version as the current one. No
+    // nodes are changed: Nix reads versions 5 through 7 with the same parser
+    // and writes them back out as version 7, so there is no structural
+    // difference between them to migrate. This is only tested on version 7
+    // locks relabeled as older ones, not on files written by a Nix release
+    // that emitted version 5 or 6.
+    // <https://github.com/NixOS/nix/blob/c9211b0b2d52a26ed666780b763b39a5bddd3fb3/src/libflake/lockfile.cc>
+    pub fn migrate_from(mut value: serde_json::Value) -> Result<Self, MigrationError> {
+        let version = value["version"]
+            .as_u64()
+            .ok_or(MigrationError::MissingVersion)?;
+        if !(MIN_SUPPORTED_LOCK_VERSION.into()..=MAX_SUPPORTED_LOCK_VERSION.into())
+            .contains(&version)
+        {
+            return Err(MigrationError::UnsupportedVersion(version));
+        }
+        value["version"] = MAX_SUPPORTED_LOCK_VERSION.into();
+        Ok(serde_json::from_value(value)?)
+    }
+
+    // The serde derives are shared with `serde_json`; `locked` and `original`
+    // are still `serde_json::Value`s, which deserialize from any format.
+    // `benches/lock_parse.rs` compares the two on a large lock.
+    #[cfg(feature = "sonic")]
+    pub fn from_slice_fast(json: &[u8]) -> Result<Self, sonic_rs::Error> {
+        sonic_rs::from_slice(json)
+    }
+
+    // Formats the lock file the way Nix writes it.
+    pub fn to_lock_string(&self) -> String {
+        let mut json = serde_json::to_string_pretty(self).unwrap();
+        json.push('\n');
+        json
+    }
+
+    // Compact JSON with every object's keys sorted, so that locks that differ
+    // only in formatting or in the order of their nodes and inputs give the
+    // same string.
+    pub fn canonical_json(&self) -> String {
+        let value = serde_json::to_value(self).unwrap();
+        serde_json::to_string(&SortedKeys(&value)).unwrap()
+    }
+
+    pub fn content_hash(&self) -> Hash {
+        Hash::compute(HashAlgo::Sha256, self.canonical_json())
+    }
+
     pub fn root(&self) -> Option<Ref<'_, Node>> {
         self.nodes.get(&self.root).map(RefCell::borrow)
     }
 
+    // Nix always writes the root as a node without `locked`, but generated
+    // lock files may point `root` at a locked node or at no node at all.
+    pub fn is_well_formed_root(&self) -> bool {
+        matches!(self.root().as_deref(), Some(Node::Unlocked(_)))
+    }
+
     pub fn root_index(&self) -> &str {
         &self.root
     }
@@ -188,6 +355,76 @@ impl LockFile {
         self.nodes.keys().map(String::as_str)
     }
 
+    pub fn locked_inputs(&self) -> impl Iterator<Item = (&str, Ref<'_, LockedNode>)> {
+        self.nodes.iter().filter_map(|(index, node)| {
+            let locked = Ref::filter_map(node.borrow(), |node| match node {
+                Node::Locked(locked) => Some(locked),
+                Node::Unlocked(_) => None,
+            });
+            Some((index.as_str(), locked.ok()?))
+        })
+    }
+
+    // The locked nodes whose `narHash` is `nar_hash`, which may be in any
+    // encoding Nix accepts. Nix only writes SHA-256 NAR hashes.
+    pub fn find_by_nar_hash(
+        &self,
+        nar_hash: &str,
+    ) -> Result<Vec<(&str, Ref<'_, LockedNode>)>, ParseError> {
+        let nar_hash = Hash::parse_as(nar_hash, HashAlgo::Sha256)?;
+        Ok(self
+            .locked_inputs()
+            .filter(|(_, node)| {
+                node.locked["narHash"]
+                    .as_str()
+                    .is_some_and(|stored| nar_hash.matches_input(stored))
+            })
+            .collect())
+    }
+
+    // Fails with the index of every node that isn't pinned, either because it
+    // has no `locked` attributes at all, or because they don't pass
+    // `LockedNode::validate_as_locked`. Only the root may be unlocked.
+    pub fn assert_reproducible(&self) -> Result<(), Vec<String>> {
+        let unpinned = self
+            .nodes
+            .iter()
+            .filter(|(index, node)| match &*node.borrow() {
+                Node::Locked(node) => node.validate_as_locked().is_err(),
+                Node::Unlocked(_) => **index != self.root,
+            })
+            .map(|(index, _)| index.clone())
+            .collect::<Vec<_>>();
+        if unpinned.is_empty() {
+            Ok(())
+        } else {
+            Err(unpinned)
+        }
+    }
+
+    pub fn summary(&self) -> LockSummary {
+        let mut summary = LockSummary {
+            nodes: self.nodes.len(),
+            ..Default::default()
+        };
+        for node in self.nodes.values() {
+            let node = node.borrow();
+            match &*node {
+                Node::Locked(locked) => {
+                    if let Some(input_type) = locked.locked["type"].as_str() {
+                        *summary.by_type.entry(input_type.to_string()).or_default() += 1;
+                    }
+                }
+                Node::Unlocked(_) => summary.unlocked += 1,
+            }
+            summary.follows += node
+                .iter_edges()
+                .filter(|(_, edge)| matches!(**edge, NodeEdge::Follows(_)))
+                .count();
+        }
+        summary
+    }
+
     pub fn get_node(&self, index: impl AsRef<str>) -> Option<Ref<'_, Node>> {
         self.nodes.get(index.as_ref()).map(RefCell::borrow)
     }
@@ -202,6 +439,205 @@ impl LockFile {
             .map(|cell| cell.into_inner())
     }
 
+    pub fn rewrite_input(
+        &mut self,
+        name: impl AsRef<str>,
+        locked: serde_json::Value,
+    ) -> Result<(), LockError> {
+        let name = name.as_ref();
+        let index = {
+            let root = self
+                .root()
+                .ok_or_else(|| LockError::MissingInput(name.into()))?;
+            let edge = root
+                .get_edge(name)
+                .ok_or_else(|| LockError::MissingInput(name.into()))?;
+            self.resolve_edge(&edge)
+                .ok_or_else(|| LockError::Unresolved(name.into()))?
+        };
+        match self.nodes.get_mut(&index).map(RefCell::get_mut) {
+            Some(Node::Locked(node)) => {
+                node.locked = locked;
+                Ok(())
+            }
+            Some(Node::Unlocked(_)) => Err(LockError::NotLocked(index)),
+            None => Err(LockError::Unresolved(name.into())),
+        }
+    }
+
+    // Adds the nodes of `other` as the input `under` of the root node. The
+    // root can't already have an input by that name, since replacing it would
+    // leave the nodes it reached behind. The root of `other` becomes a locked
+    // node, with the `locked` and `original` attributes of the subflake, since
+    // Nix requires them on every node but the root.
+    pub fn merge(
+        &mut self,
+        other: &LockFile,
+        under: impl AsRef<str>,
+        locked: serde_json::Value,
+        original: serde_json::Value,
+    ) -> Result<(), LockError> {
+        let under = under.as_ref();
+        if self
+            .root()
+            .is_some_and(|root| root.iter_edges().any(|(name, _)| name == under))
+        {
+            return Err(LockError::InputExists(under.into()));
+        }
+        let mut renames = IndexMap::<String, String>::new();
+        let mut taken = HashSet::new();
+        let mut queue = VecDeque::from([other.root.clone()]);
+        while let Some(index) = queue.pop_front() {
+            if renames.contains_key(&index) {
+                continue;
+            }
+            let base = if index == other.root {
+                under
+            } else {
+                base_node_name(&index)
+            };
+            let name = (1..)
+                .map(|n| match n {
+                    1 => base.to_string(),
+                    n => format!("{base}_{n}"),
+                })
+                .find(|name| !self.nodes.contains_key(name) && !taken.contains(name))
+                .unwrap();
+            taken.insert(name.clone());
+            if let Some(node) = other.get_node(&index) {
+                queue.extend(
+                    node.iter_edges()
+                        .filter_map(|(_, edge)| NodeEdge::index(&edge).map(Into::into)),
+                );
+            }
+            renames.insert(index, name);
+        }
+
+        for (index, name) in &renames {
+            let Some(node) = other.get_node(index) else {
+                continue;
+            };
+            let mut node = if *index == other.root {
+                Node::Locked(LockedNode {
+                    flake: true,
+                    inputs: node.edges().clone(),
+                    locked: locked.clone(),
+                    original: original.clone(),
+                    parent: None,
+                })
+            } else {
+                node.clone()
+            };
+            if let Node::Locked(LockedNode {
+                parent: Some(parent),
+                ..
+            }) = &mut node
+            {
+                parent.insert(0, under.to_string());
+            }
+            for (_, mut edge) in node.iter_edges_mut() {
+                *edge = match &*edge {
+                    NodeEdge::Indexed(index) => NodeEdge::Indexed(renames[index].clone()),
+                    NodeEdge::Follows(path) => std::iter::once(under)
+                        .chain(path.iter().map(String::as_str))
+                        .collect(),
+                };
+            }
+            self.nodes.insert(name.clone(), RefCell::new(node));
+        }
+
+        if let Some(root) = self.nodes.get_mut(&self.root) {
+            let edge = NodeEdge::Indexed(renames[&other.root].clone());
+            root.get_mut()
+                .edges_mut()
+                .insert(under.into(), RefCell::new(edge));
+        }
+        Ok(())
+    }
+
+    // Replaces every `follows` edge with the index of the node it resolves
+    // to. Nothing is changed if any of them doesn't resolve.
+    pub fn flatten_follows(&mut self) -> Result<(), LockError> {
+        let mut resolved = Vec::new();
+        for (index, node) in &self.nodes {
+            for (name, edge) in node.borrow().iter_edges() {
+                if let NodeEdge::Follows(path) = &*edge {
+                    let target = self
+                        .follow_path(path)
+                        .ok_or_else(|| LockError::Unresolved(edge.to_string()))?;
+                    resolved.push((index.clone(), name.to_string(), target));
+                }
+            }
+        }
+        for (index, name, target) in resolved {
+            let node = self.nodes[&index].get_mut();
+            node.edges_mut()[&name] = RefCell::new(NodeEdge::Indexed(target));
+        }
+        Ok(())
+    }
+
+    // Maps the names of a node's inputs to the nodes they resolve to, leaving
+    // out any that don't. As in Nix, `follows` paths start from the root no
+    // matter which node they belong to.
+    pub fn inputs_of(&self, index: impl AsRef<str>) -> Option<IndexMap<String, Ref<'_, Node>>> {
+        let node = self.get_node(index)?;
+        let inputs = node
+            .iter_edges()
+            .filter_map(|(name, edge)| {
+                let target = self.get_node(self.resolve_edge(&edge)?)?;
+                Some((name.to_string(), target))
+            })
+            .collect();
+        Some(inputs)
+    }
+
+    // Renames every node the way Nix does when it writes a lock file: nodes
+    // are named after the input they are first reached through, walking
+    // depth-first from the root with inputs in sorted order, and `_2`, `_3`
+    // and so on are appended on collisions. Unreachable nodes are dropped.
+    // <https://github.com/NixOS/nix/blob/c9211b0b2d52a26ed666780b763b39a5bddd3fb3/src/libflake/lockfile.cc>
+    pub fn renumber(&mut self) {
+        let mut renames = IndexMap::<String, String>::new();
+        let mut stack = vec![(self.root.clone(), "root".to_string())];
+        while let Some((index, base)) = stack.pop() {
+            if renames.contains_key(&index) {
+                continue;
+            }
+            let name = (1..)
+                .map(|n| match n {
+                    1 => base.clone(),
+                    n => format!("{base}_{n}"),
+                })
+                .find(|name| !renames.values().any(|taken| taken == name))
+                .unwrap();
+            if let Some(node) = self.get_node(&index) {
+                let mut children = node
+                    .iter_edges()
+                    .filter_map(|(name, edge)| Some((edge.index()?.to_string(), name.to_string())))
+                    .collect::<Vec<_>>();
+                children.sort_by(|(_, a), (_, b)| b.cmp(a));
+                stack.extend(children);
+            }
+            renames.insert(index, name);
+        }
+
+        let mut nodes = IndexMap::new();
+        for (index, name) in &renames {
+            let Some(mut node) = self.nodes.shift_remove(index) else {
+                continue;
+            };
+            for (_, mut edge) in node.get_mut().iter_edges_mut() {
+                if let NodeEdge::Indexed(index) = &*edge {
+                    *edge = NodeEdge::Indexed(renames[index].clone());
+                }
+            }
+            nodes.insert(name.clone(), node);
+        }
+        nodes.sort_keys();
+        self.nodes = nodes;
+        self.root = "root".into();
+    }
+
     pub fn resolve_edge(&self, edge: &NodeEdge) -> Option<String> {
         match edge {
             NodeEdge::Indexed(index) => Some(index.to_owned()),
@@ -214,6 +650,29 @@ impl LockFile {
             self.resolve_edge(&*self.get_node(index)?.get_edge(name)?)
         })
     }
+
+    // Takes an attribute path as written in `flake.nix`, such as
+    // `devenv.inputs.nixpkgs`, optionally starting with `inputs.`.
+    pub fn get_input_path(&self, path: &str) -> Option<Ref<'_, Node>> {
+        let path = path.strip_prefix("inputs.").unwrap_or(path);
+        self.get_node(self.follow_path(path.split(".inputs."))?)
+    }
+
+    pub fn input_rev(&self, name: impl AsRef<str>) -> Option<Ref<'_, str>> {
+        let node = self.get_node(self.follow_path([name])?)?;
+        Ref::filter_map(node, |node| match node {
+            Node::Locked(locked) => locked.locked["rev"].as_str(),
+            Node::Unlocked(_) => None,
+        })
+        .ok()
+    }
+}
+
+fn base_node_name(index: &str) -> &str {
+    match index.rsplit_once('_') {
+        Some((base, n)) if !base.is_empty() && n.parse::<u32>().is_ok() => base,
+        _ => index,
+    }
 }
 
 impl Default for LockFile {
@@ -221,3 +680,899 @@ impl Default for LockFile {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use serde_json::json;
+    use test_case::{test_case, test_matrix};
+
+    use super::{
+        LockError, LockFile, LockedNode, MAX_SUPPORTED_LOCK_VERSION, MigrationError, Node, NodeEdge,
+    };
+    #[cfg(feature = "sonic")]
+    use crate::fetchers::git::GitInputScheme;
+    use crate::hash::{Hash, HashAlgo, HashFormat};
+
+    static SAMPLE: &str = r#"{
+  "nodes": {
+    "flake-utils": {
+      "inputs": {
+        "systems": "systems"
+      },
+      "locked": {
+        "lastModified": 1731533236,
+        "narHash": "sha256-l0KFg5HjrsfsO/JpG+r7fRrqm12kzFHyUHqHCVpMMbI=",
+        "owner": "numtide",
+        "repo": "flake-utils",
+        "rev": "11707dc2f618dd54ca8739b309ec4fc024de578b",
+        "type": "github"
+      },
+      "original": {
+        "owner": "numtide",
+        "repo": "flake-utils",
+        "type": "github"
+      }
+    },
+    "home-manager": {
+      "inputs": {
+        "nixpkgs": [
+          "nixpkgs"
+        ]
+      },
+      "locked": {
+        "lastModified": 1736277415,
+        "narHash": "sha256-kPDXF6cIPsVqSPDeUpYP6NPr0GE3BX7xbEVwMSy4J7A=",
+        "owner": "nix-community",
+        "repo": "home-manager",
+        "rev": "d26fd6b0c2b1c5bf6cf5ff2a1fd0d8e5a0e5b0a9",
+        "type": "github"
+      },
+      "original": {
+        "owner": "nix-community",
+        "repo": "home-manager",
+        "type": "github"
+      }
+    },
+    "nixpkgs": {
+      "locked": {
+        "lastModified": 1736200483,
+        "narHash": "sha256-JO+lFN2HsCwSLMUWXHeOad6QUxOuwe9UOAF/iSl1J4I=",
+        "owner": "NixOS",
+        "repo": "nixpkgs",
+        "rev": "3f0a8ac25fb674611b98089ca3a5dd6480175751",
+        "type": "github"
+      },
+      "original": {
+        "owner": "NixOS",
+        "ref": "nixos-unstable",
+        "repo": "nixpkgs",
+        "type": "github"
+      }
+    },
+    "root": {
+      "inputs": {
+        "flake-utils": "flake-utils",
+        "home-manager": "home-manager",
+        "nixpkgs": "nixpkgs"
+      }
+    },
+    "systems": {
+      "locked": {
+        "lastModified": 1681028828,
+        "narHash": "sha256-Vy1rq5AaRuLzOxct8nz4T6wlgyUR7zLU309k9mBC768=",
+        "owner": "nix-systems",
+        "repo": "default",
+        "rev": "da67096a3b9bf56a91d16901293e51ba5b49a27e",
+        "type": "github"
+      },
+      "original": {
+        "owner": "nix-systems",
+        "repo": "default",
+        "type": "github"
+      }
+    }
+  },
+  "root": "root",
+  "version": 7
+}"#;
+
+    fn sample() -> LockFile {
+        serde_json::from_str(SAMPLE).unwrap()
+    }
+
+    fn github(repo: &str) -> serde_json::Value {
+        json!({
+            "locked": { "owner": "owner", "repo": repo, "rev": repo, "type": "github" },
+            "original": { "owner": "owner", "repo": repo, "type": "github" }
+        })
+    }
+
+    fn lock(nodes: serde_json::Value) -> LockFile {
+        serde_json::from_value(json!({ "nodes": nodes, "root": "root", "version": 7 })).unwrap()
+    }
+
+    // Merges `child` as if it were fetched from a GitHub repository named
+    // after `under`.
+    fn merge(parent: &mut LockFile, child: &LockFile, under: &str) -> Result<(), LockError> {
+        let source = github(under);
+        let (locked, original) = (source["locked"].clone(), source["original"].clone());
+        parent.merge(child, under, locked, original)
+    }
+
+    fn unlocked_indices(lock: &LockFile) -> Vec<String> {
+        lock.nodes
+            .iter()
+            .filter(|(_, node)| matches!(*node.borrow(), Node::Unlocked(_)))
+            .map(|(index, _)| index.clone())
+            .collect()
+    }
+
+    fn locked_repo(lock: &LockFile, index: &str) -> String {
+        match &*lock.get_node(index).unwrap() {
+            Node::Locked(node) => node.locked["repo"].as_str().unwrap().to_string(),
+            Node::Unlocked(_) => panic!("node `{index}` is not locked"),
+        }
+    }
+
+    #[test]
+    fn get_input_path() {
+        let lock = sample();
+        let systems = lock.get_input_path("flake-utils.inputs.systems").unwrap();
+        assert_eq!(*systems, *lock.get_node("systems").unwrap());
+        let nixpkgs = lock.get_input_path("inputs.home-manager.inputs.nixpkgs");
+        assert_eq!(*nixpkgs.unwrap(), *lock.get_node("nixpkgs").unwrap());
+        assert!(lock.get_input_path("home-manager.inputs.systems").is_none());
+        assert!(lock.get_input_path("flake-utils.systems").is_none());
+    }
+
+    #[cfg(feature = "sonic")]
+    #[test]
+    fn from_slice_fast() {
+        let fast = LockFile::from_slice_fast(SAMPLE.as_bytes()).unwrap();
+        assert_eq!(fast, sample());
+
+        let git = json!({
+            "url": "https://example.com/repo.git",
+            "rev": "0123456789abcdef0123456789abcdef01234567",
+            "narHash": "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+            "lastModified": 1700000000
+        })
+        .to_string();
+        assert_eq!(
+            sonic_rs::from_str::<GitInputScheme>(&git).unwrap(),
+            serde_json::from_str::<GitInputScheme>(&git).unwrap()
+        );
+    }
+
+    #[test_case(include_str!("fixtures/github-follows.lock") ; "github with follows")]
+    #[test_case(include_str!("fixtures/git-tarball.lock") ; "git and tarball")]
+    #[test_case(include_str!("fixtures/path.lock") ; "relative path")]
+    fn fixture_roundtrip(fixture: &str) {
+        let lock = serde_json::from_str::<LockFile>(fixture).unwrap();
+        assert_eq!(lock.to_lock_string(), fixture);
+    }
+
+    #[test_case(include_str!("fixtures/github-follows.lock") ; "github with follows")]
+    #[test_case(include_str!("fixtures/git-tarball.lock") ; "git and tarball")]
+    #[test_case(include_str!("fixtures/path.lock") ; "relative path")]
+    fn renumber_nix_lock(fixture: &str) {
+        let mut lock = serde_json::from_str::<LockFile>(fixture).unwrap();
+        lock.renumber();
+        assert_eq!(lock.to_lock_string(), fixture);
+    }
+
+    #[test]
+    fn renumber() {
+        let mut nodes = json!({
+            "top": { "inputs": { "utils": "utils-abc", "nixpkgs": "pkgs-old" } },
+            "utils-abc": github("flake-utils"),
+            "pkgs-old": github("nixpkgs"),
+            "pkgs-new": github("nixpkgs"),
+            "systems-1": github("systems"),
+            "unreachable": github("unreachable"),
+        });
+        nodes["utils-abc"]["inputs"] = json!({
+            "systems": "systems-1",
+            "nixpkgs": "pkgs-new",
+            "pkgs": ["nixpkgs"],
+        });
+        nodes["pkgs-new"]["inputs"] = json!({ "systems": "systems-1" });
+        let mut lock = serde_json::from_value::<LockFile>(json!({
+            "nodes": nodes, "root": "top", "version": 7
+        }))
+        .unwrap();
+
+        lock.renumber();
+        assert_eq!(lock.root_index(), "root");
+        assert_eq!(
+            lock.node_indices().collect::<Vec<_>>(),
+            ["nixpkgs", "nixpkgs_2", "root", "systems", "utils"]
+        );
+        assert_eq!(locked_repo(&lock, "nixpkgs_2"), "nixpkgs");
+        assert_eq!(locked_repo(&lock, "systems"), "systems");
+        let utils = lock.get_node("utils").unwrap();
+        assert_eq!(
+            *utils.get_edge("nixpkgs").unwrap(),
+            NodeEdge::Indexed("nixpkgs_2".into())
+        );
+        assert_eq!(
+            *utils.get_edge("pkgs").unwrap(),
+            NodeEdge::Follows(vec!["nixpkgs".into()])
+        );
+        drop(utils);
+        let repos = [
+            "nixpkgs",
+            "utils",
+            "utils.inputs.pkgs",
+            "utils.inputs.systems",
+        ]
+        .map(|path| {
+            let index = lock.follow_path(path.split(".inputs.")).unwrap();
+            locked_repo(&lock, &index)
+        });
+        assert_eq!(repos, ["nixpkgs", "flake-utils", "nixpkgs", "systems"]);
+    }
+
+    #[test]
+    fn merge_prefixes_parent() {
+        let mut parent = lock(json!({ "root": { "inputs": {} } }));
+        let child = serde_json::from_str(include_str!("fixtures/path.lock")).unwrap();
+        merge(&mut parent, &child, "child").unwrap();
+        let Node::Locked(sub) = &*parent.get_node("sub").unwrap() else {
+            panic!("`sub` is not locked");
+        };
+        assert_eq!(sub.parent.as_deref(), Some(&["child".to_string()][..]));
+    }
+
+    #[test]
+    fn input_rev() {
+        let sample = sample();
+        assert_eq!(
+            sample.input_rev("nixpkgs").as_deref(),
+            Some("3f0a8ac25fb674611b98089ca3a5dd6480175751")
+        );
+        assert!(sample.input_rev("systems").is_none());
+
+        let tarball = lock(json!({
+            "src": {
+                "flake": false,
+                "locked": { "type": "tarball", "url": "https://example.com/src.tar.gz" },
+                "original": { "type": "tarball", "url": "https://example.com/src.tar.gz" }
+            },
+            "root": { "inputs": { "src": "src" } }
+        }));
+        assert!(tarball.input_rev("src").is_none());
+    }
+
+    #[test]
+    fn root_without_inputs() {
+        let mut lock = lock(json!({ "root": {} }));
+        assert!(lock.is_well_formed_root());
+        assert_eq!(lock.root().unwrap().iter_edges().count(), 0);
+        assert_eq!(lock.follow_path(["nixpkgs"]), None);
+        assert!(lock.get_input_path("nixpkgs").is_none());
+        assert!(lock.input_rev("nixpkgs").is_none());
+        assert_eq!(
+            lock.rewrite_input("nixpkgs", json!({})),
+            Err(LockError::MissingInput("nixpkgs".into()))
+        );
+        assert_eq!(
+            serde_json::to_value(&lock).unwrap()["nodes"]["root"],
+            json!({})
+        );
+    }
+
+    #[test]
+    fn nonstandard_root() {
+        let mut nodes = json!({ "nixpkgs": github("nixpkgs") });
+        nodes["root"] = github("root");
+        assert!(!lock(nodes.clone()).is_well_formed_root());
+
+        let mut locked_root = lock(nodes.clone());
+        locked_root.root = "nixpkgs".into();
+        assert!(!locked_root.is_well_formed_root());
+        assert!(locked_root.input_rev("nixpkgs").is_none());
+
+        let mut missing_root = lock(nodes);
+        missing_root.root = "missing".into();
+        assert!(!missing_root.is_well_formed_root());
+        assert!(missing_root.root().is_none());
+        assert_eq!(missing_root.follow_path(["nixpkgs"]), None);
+        assert!(LockFile::new().is_well_formed_root());
+    }
+
+    #[test]
+    fn content_hash() {
+        let lock = sample();
+        let json = lock.canonical_json();
+        assert!(
+            json.starts_with(
+                r#"{"nodes":{"flake-utils":{"inputs":{"systems":"systems"},"locked":"#
+            )
+        );
+        assert!(!json.contains(char::is_whitespace));
+
+        // Writes every object with its keys in reverse order, which reverses
+        // the order of the nodes and of their inputs.
+        fn reversed(value: &serde_json::Value) -> String {
+            match value {
+                serde_json::Value::Object(map) => {
+                    let entries = map
+                        .iter()
+                        .rev()
+                        .map(|(key, value)| format!("{key:?}:\n\t{}", reversed(value)));
+                    format!("{{ {} }}", entries.collect::<Vec<_>>().join(", "))
+                }
+                value => value.to_string(),
+            }
+        }
+        let mut value = serde_json::from_str::<serde_json::Value>(SAMPLE).unwrap();
+        let reordered = serde_json::from_str::<LockFile>(&reversed(&value)).unwrap();
+        assert_eq!(reordered.node_indices().next(), Some("systems"));
+        let first_input = |lock: &LockFile| lock.root().unwrap().edges().keys().next().cloned();
+        assert_ne!(first_input(&reordered), first_input(&lock));
+        assert_ne!(reordered.to_lock_string(), lock.to_lock_string());
+        assert_eq!(reordered.canonical_json(), json);
+        assert_eq!(reordered.content_hash(), lock.content_hash());
+
+        value["nodes"]["nixpkgs"]["locked"]["rev"] =
+            "0000000000000000000000000000000000000000".into();
+        let changed = serde_json::from_value::<LockFile>(value).unwrap();
+        assert_ne!(changed.content_hash(), lock.content_hash());
+    }
+
+    #[test]
+    fn find_by_nar_hash() {
+        let sample = sample();
+        let sri = "sha256-JO+lFN2HsCwSLMUWXHeOad6QUxOuwe9UOAF/iSl1J4I=";
+        let hash = Hash::parse(sri).unwrap();
+        let hex = hash.to_string(&HashFormat::Base16, false);
+        let nix32 = hash.to_string(&HashFormat::Nix32, true);
+        for query in [sri, &hex, &nix32] {
+            let found = sample.find_by_nar_hash(query).unwrap();
+            let indices = found.iter().map(|(index, _)| *index).collect::<Vec<_>>();
+            assert_eq!(indices, ["nixpkgs"], "{query}");
+            assert_eq!(found[0].1.locked["narHash"], sri);
+        }
+
+        let empty = Hash::compute(HashAlgo::Sha256, b"");
+        assert!(
+            sample
+                .find_by_nar_hash(&empty.to_string(&HashFormat::Sri, true))
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            sample
+                .find_by_nar_hash("sha1-2jmj7l5rSw0yVb/vlWAYkK/YBwk=")
+                .is_err()
+        );
+
+        let mut nodes = json!({
+            "root": { "inputs": { "a": "a", "b": "b", "c": "c" } },
+            "a": github("a"),
+            "b": github("b"),
+            "c": github("c"),
+        });
+        nodes["a"]["locked"]["narHash"] = sri.into();
+        nodes["c"]["locked"]["narHash"] = hex.clone().into();
+        let lock = lock(nodes);
+        let found = lock.find_by_nar_hash(&nix32).unwrap();
+        let indices = found.iter().map(|(index, _)| *index).collect::<Vec<_>>();
+        assert_eq!(indices, ["a", "c"]);
+    }
+
+    #[test]
+    fn inputs_of() {
+        let mut nodes = json!({
+            "root": { "inputs": { "home-manager": "home-manager", "nixpkgs": "nixpkgs" } },
+            "home-manager": github("home-manager"),
+            "nixpkgs": github("nixpkgs"),
+            "nixpkgs_2": github("nixpkgs"),
+        });
+        nodes["home-manager"]["inputs"] = json!({
+            "nixpkgs": ["nixpkgs"],
+            // Relative to the root, which has no `utils`.
+            "systems": ["utils"],
+            "utils": "nixpkgs_2",
+        });
+        let lock = lock(nodes);
+
+        let inputs = lock.inputs_of("home-manager").unwrap();
+        assert_eq!(inputs.keys().collect::<Vec<_>>(), ["nixpkgs", "utils"]);
+        assert_eq!(*inputs["nixpkgs"], *lock.get_node("nixpkgs").unwrap());
+        assert_eq!(*inputs["utils"], *lock.get_node("nixpkgs_2").unwrap());
+
+        let root = lock.inputs_of("root").unwrap();
+        assert_eq!(root.len(), 2);
+        assert!(lock.inputs_of("nixpkgs").unwrap().is_empty());
+        assert!(lock.inputs_of("missing").is_none());
+    }
+
+    #[test]
+    fn flatten_follows() {
+        let mut lock = sample();
+        let before = lock.clone();
+        lock.flatten_follows().unwrap();
+        assert_eq!(lock.summary().follows, 0);
+        assert_eq!(
+            lock.node_indices().collect::<Vec<_>>(),
+            before.node_indices().collect::<Vec<_>>()
+        );
+        for path in ["home-manager.inputs.nixpkgs", "flake-utils.inputs.systems"] {
+            assert_eq!(
+                *lock.get_input_path(path).unwrap(),
+                *before.get_input_path(path).unwrap()
+            );
+        }
+        let home_manager = lock.get_node("home-manager").unwrap();
+        assert_eq!(
+            *home_manager.get_edge("nixpkgs").unwrap(),
+            NodeEdge::Indexed("nixpkgs".into())
+        );
+    }
+
+    #[test]
+    fn flatten_follows_unresolved() {
+        let mut nodes = json!({ "root": { "inputs": { "a": "a" } }, "a": github("a") });
+        nodes["a"]["inputs"] = json!({ "nixpkgs": ["nixpkgs"] });
+        let mut lock = lock(nodes);
+        let before = lock.clone();
+        assert_eq!(
+            lock.flatten_follows(),
+            Err(LockError::Unresolved("nixpkgs".into()))
+        );
+        assert_eq!(lock, before);
+    }
+
+    #[test]
+    fn locked_inputs() {
+        let lock = sample();
+        let indices = lock
+            .locked_inputs()
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            indices,
+            ["flake-utils", "home-manager", "nixpkgs", "systems"]
+        );
+        let (_, nixpkgs) = lock.locked_inputs().nth(2).unwrap();
+        assert_eq!(nixpkgs.locked["repo"], "nixpkgs");
+    }
+
+    #[test]
+    fn summary() {
+        let lock = lock(json!({
+            "flake-utils": github("flake-utils"),
+            "home-manager": {
+                "inputs": { "nixpkgs": ["nixpkgs"], "utils": ["flake-utils"] },
+                "locked": { "type": "git", "url": "https://example.com/hm.git", "rev": "abc" },
+                "original": { "type": "git", "url": "https://example.com/hm.git" }
+            },
+            "nixpkgs": github("nixpkgs"),
+            "src": {
+                "flake": false,
+                "locked": { "type": "tarball", "url": "https://example.com/src.tar.gz" },
+                "original": { "type": "tarball", "url": "https://example.com/src.tar.gz" }
+            },
+            "root": {
+                "inputs": {
+                    "flake-utils": "flake-utils",
+                    "home-manager": "home-manager",
+                    "nixpkgs": "nixpkgs",
+                    "src": "src",
+                    "systems": ["flake-utils", "systems"]
+                }
+            }
+        }));
+        let summary = lock.summary();
+        assert_eq!(summary.nodes, 5);
+        assert_eq!(
+            summary.by_type,
+            BTreeMap::from([
+                ("git".to_string(), 1),
+                ("github".to_string(), 2),
+                ("tarball".to_string(), 1),
+            ])
+        );
+        assert_eq!(summary.follows, 3);
+        assert_eq!(summary.unlocked, 1);
+    }
+
+    // These are version 7 locks labeled as older ones, since the versions
+    // only differ in their label.
+    #[test_matrix(
+        [
+            include_str!("fixtures/github-follows.lock"),
+            include_str!("fixtures/git-tarball.lock"),
+            include_str!("fixtures/path.lock"),
+        ],
+        [5, 6, 7]
+    )]
+    fn migrate_from(fixture: &str, version: u32) {
+        let mut value = serde_json::from_str::<serde_json::Value>(fixture).unwrap();
+        value["version"] = version.into();
+        let lock = LockFile::migrate_from(value).unwrap();
+        assert_eq!(lock.version(), MAX_SUPPORTED_LOCK_VERSION);
+        assert_eq!(lock.to_lock_string(), fixture);
+    }
+
+    #[test]
+    fn migrate_from_unsupported() {
+        let value = json!({ "nodes": {}, "root": "root", "version": 4 });
+        assert!(matches!(
+            LockFile::migrate_from(value),
+            Err(MigrationError::UnsupportedVersion(4))
+        ));
+        let value = json!({ "nodes": {}, "root": "root" });
+        assert!(matches!(
+            LockFile::migrate_from(value),
+            Err(MigrationError::MissingVersion)
+        ));
+        let value = json!({ "nodes": [], "root": "root", "version": 6 });
+        assert!(matches!(
+            LockFile::migrate_from(value),
+            Err(MigrationError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn rewrite_input() {
+        let mut lock = sample();
+        let locked = json!({
+            "lastModified": 1736549401,
+            "narHash": "sha256-ibkQrMHxF/7TqAYcQE+tOnIsSEzXmMegzyBWje6ZasU=",
+            "owner": "NixOS",
+            "repo": "nixpkgs",
+            "rev": "1dab772dd4a68a7bba5d9460685547ff8e17d899",
+            "type": "github"
+        });
+        lock.rewrite_input("nixpkgs", locked.clone()).unwrap();
+
+        let original = sample();
+        for index in original.node_indices() {
+            let (before, after) = (original.get_node(index), lock.get_node(index));
+            match (&*before.unwrap(), &*after.unwrap()) {
+                (Node::Locked(before), Node::Locked(after)) if index == "nixpkgs" => {
+                    assert_eq!(after.locked, locked);
+                    assert_eq!(before.original, after.original);
+                    assert_eq!(before.inputs, after.inputs);
+                }
+                (before, after) => assert_eq!(before, after),
+            }
+        }
+        assert_eq!(
+            original.node_indices().collect::<Vec<_>>(),
+            lock.node_indices().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn rewrite_input_missing() {
+        let mut lock = sample();
+        assert_eq!(
+            lock.rewrite_input("systems", json!({})),
+            Err(LockError::MissingInput("systems".into()))
+        );
+        assert_eq!(lock, sample());
+    }
+
+    fn locked_node(locked: serde_json::Value) -> LockedNode {
+        serde_json::from_value(json!({ "locked": locked, "original": {} })).unwrap()
+    }
+
+    #[test]
+    fn modified_time() {
+        let node = locked_node(json!({ "type": "github", "lastModified": 1700000000 }));
+        assert_eq!(
+            node.modified_time(),
+            Some(UNIX_EPOCH + Duration::from_secs(1700000000))
+        );
+        let node = locked_node(json!({ "type": "path", "lastModified": -60 }));
+        assert_eq!(
+            node.modified_time(),
+            Some(UNIX_EPOCH - Duration::from_secs(60))
+        );
+        assert_eq!(locked_node(json!({ "type": "path" })).modified_time(), None);
+    }
+
+    #[test]
+    fn validate_as_locked() {
+        let lock = sample();
+        for index in lock.node_indices() {
+            if let Node::Locked(node) = &*lock.get_node(index).unwrap() {
+                assert_eq!(node.validate_as_locked(), Ok(()));
+            }
+        }
+        let tarball = json!({
+            "type": "tarball",
+            "url": "https://example.com/source.tar.gz",
+            "narHash": "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+        });
+        assert_eq!(locked_node(tarball).validate_as_locked(), Ok(()));
+    }
+
+    #[test]
+    fn assert_reproducible() {
+        assert_eq!(sample().assert_reproducible(), Ok(()));
+
+        let mut nodes = json!({
+            "root": { "inputs": { "a": "a", "b": "b", "c": "c", "d": "d" } },
+            "a": github("a"),
+            "b": github("b"),
+            "c": {
+                "locked": { "type": "indirect", "id": "c" },
+                "original": { "type": "indirect", "id": "c" }
+            },
+            "d": {},
+        });
+        nodes["b"]["locked"].as_object_mut().unwrap().remove("rev");
+        nodes["a"]["inputs"] = json!({ "e": "e" });
+        nodes["e"] = json!({
+            "locked": { "type": "tarball", "url": "https://example.com/e.tar.gz" },
+            "original": { "type": "tarball", "url": "https://example.com/e.tar.gz" }
+        });
+        assert_eq!(
+            lock(nodes).assert_reproducible(),
+            Err(vec!["b".into(), "c".into(), "d".into(), "e".into()])
+        );
+    }
+
+    #[test]
+    fn validate_as_locked_underspecified() {
+        let github = json!({ "type": "github", "owner": "NixOS", "repo": "nixpkgs" });
+        assert_eq!(
+            locked_node(github).validate_as_locked(),
+            Err(LockError::MissingAttr {
+                input_type: "github".into(),
+                attr: "rev"
+            })
+        );
+        let tarball = json!({ "type": "tarball", "url": "https://example.com/source.tar.gz" });
+        assert_eq!(
+            locked_node(tarball).validate_as_locked(),
+            Err(LockError::MissingAttr {
+                input_type: "tarball".into(),
+                attr: "narHash"
+            })
+        );
+        let indirect = json!({ "type": "indirect", "id": "nixpkgs" });
+        assert_eq!(
+            locked_node(indirect).validate_as_locked(),
+            Err(LockError::Indirect)
+        );
+        assert_eq!(
+            locked_node(json!({})).validate_as_locked(),
+            Err(LockError::MissingType)
+        );
+    }
+
+    #[test]
+    fn merge_disjoint() {
+        let mut parent = lock(json!({
+            "nixpkgs": github("nixpkgs"),
+            "root": { "inputs": { "nixpkgs": "nixpkgs" } }
+        }));
+        let child = lock(json!({
+            "flake-utils": github("flake-utils"),
+            "root": { "inputs": { "flake-utils": "flake-utils" } }
+        }));
+        merge(&mut parent, &child, "child").unwrap();
+        assert_eq!(
+            parent.node_indices().collect::<Vec<_>>(),
+            ["nixpkgs", "root", "child", "flake-utils"]
+        );
+        assert_eq!(parent.follow_path(["child"]).as_deref(), Some("child"));
+        assert_eq!(
+            parent.follow_path(["child", "flake-utils"]).as_deref(),
+            Some("flake-utils")
+        );
+        assert_eq!(locked_repo(&parent, "child"), "child");
+        assert_eq!(unlocked_indices(&parent), ["root"]);
+        let reparsed = serde_json::from_str::<LockFile>(&parent.to_lock_string()).unwrap();
+        assert_eq!(reparsed, parent);
+    }
+
+    #[test]
+    fn merge_overlapping_names() {
+        let mut parent = lock(json!({
+            "nixpkgs": github("parent-nixpkgs"),
+            "nixpkgs_2": github("parent-nixpkgs-2"),
+            "root": { "inputs": { "nixpkgs": "nixpkgs", "stable": "nixpkgs_2" } }
+        }));
+        let child = lock(json!({
+            "nixpkgs": github("child-nixpkgs"),
+            "nixpkgs_2": github("child-nixpkgs-2"),
+            "root": { "inputs": { "nixpkgs": "nixpkgs", "stable": "nixpkgs_2" } }
+        }));
+        merge(&mut parent, &child, "child").unwrap();
+
+        assert_eq!(
+            parent
+                .get_node("child")
+                .unwrap()
+                .get_edge("nixpkgs")
+                .unwrap()
+                .index(),
+            Some("nixpkgs_3")
+        );
+        assert_eq!(locked_repo(&parent, "nixpkgs"), "parent-nixpkgs");
+        assert_eq!(locked_repo(&parent, "nixpkgs_2"), "parent-nixpkgs-2");
+        assert_eq!(locked_repo(&parent, "nixpkgs_3"), "child-nixpkgs");
+        assert_eq!(locked_repo(&parent, "nixpkgs_4"), "child-nixpkgs-2");
+        let resolved = parent.follow_path(["child", "stable"]).unwrap();
+        assert_eq!(locked_repo(&parent, &resolved), "child-nixpkgs-2");
+    }
+
+    #[test]
+    fn merge_under_existing_node_name() {
+        let mut parent = lock(json!({
+            "child": github("child"),
+            "root": { "inputs": { "c": "child" } }
+        }));
+        let child = lock(json!({
+            "child": github("grandchild"),
+            "root": { "inputs": { "child": "child" } }
+        }));
+        merge(&mut parent, &child, "sub").unwrap();
+        merge(&mut parent, &child, "child").unwrap();
+
+        assert_eq!(
+            parent.node_indices().collect::<Vec<_>>(),
+            ["child", "root", "sub", "child_2", "child_3", "child_4"]
+        );
+        assert_eq!(parent.follow_path(["sub"]).as_deref(), Some("sub"));
+        assert_eq!(parent.follow_path(["child"]).as_deref(), Some("child_3"));
+        let resolved = parent.follow_path(["child", "child"]).unwrap();
+        assert_eq!(locked_repo(&parent, &resolved), "grandchild");
+        assert_eq!(locked_repo(&parent, "child"), "child");
+        assert_eq!(parent.follow_path(["c"]).as_deref(), Some("child"));
+    }
+
+    #[test]
+    fn merge_under_existing_input() {
+        let mut parent = lock(json!({
+            "child": github("child"),
+            "root": { "inputs": { "child": "child" } }
+        }));
+        let child = lock(json!({
+            "child": github("grandchild"),
+            "root": { "inputs": { "child": "child" } }
+        }));
+        let before = parent.clone();
+        assert_eq!(
+            merge(&mut parent, &child, "child"),
+            Err(LockError::InputExists("child".into()))
+        );
+        assert_eq!(parent, before);
+    }
+
+    #[test]
+    fn merge_rewrites_follows() {
+        let mut parent = lock(json!({
+            "nixpkgs": github("parent-nixpkgs"),
+            "root": { "inputs": { "nixpkgs": "nixpkgs" } }
+        }));
+        let child = lock(json!({
+            "home-manager": {
+                "inputs": { "nixpkgs": ["nixpkgs"] },
+                "locked": { "owner": "owner", "repo": "home-manager", "type": "github" },
+                "original": { "owner": "owner", "repo": "home-manager", "type": "github" }
+            },
+            "nixpkgs": github("child-nixpkgs"),
+            "root": { "inputs": { "home-manager": "home-manager", "nixpkgs": "nixpkgs" } }
+        }));
+        merge(&mut parent, &child, "child").unwrap();
+
+        let edge = parent
+            .get_node("home-manager")
+            .unwrap()
+            .get_edge("nixpkgs")
+            .unwrap()
+            .clone();
+        assert_eq!(edge, NodeEdge::from_iter(["child", "nixpkgs"]));
+        let resolved = parent.resolve_edge(&edge).unwrap();
+        assert_eq!(resolved, "nixpkgs_2");
+        assert_eq!(locked_repo(&parent, &resolved), "child-nixpkgs");
+    }
+
+    #[test]
+    fn merge_skips_unreachable() {
+        let mut parent = lock(json!({ "root": { "inputs": {} } }));
+        let child = lock(json!({
+            "nixpkgs": github("nixpkgs"),
+            "orphan": github("orphan"),
+            "root": { "inputs": { "nixpkgs": "nixpkgs" } }
+        }));
+        merge(&mut parent, &child, "child").unwrap();
+        assert_eq!(
+            parent.node_indices().collect::<Vec<_>>(),
+            ["root", "child", "nixpkgs"]
+        );
+    }
+
+    // Pins the exact wire format, so the skip predicates can't drift from
+    // what Nix writes.
+    mod serialization {
+        use test_case::test_case;
+
+        use super::super::{LockedNode, Node};
+
+        const LOCKED: &str = r#""locked":{"rev":"abc","type":"github"}"#;
+        const ORIGINAL: &str = r#""original":{"type":"github"}"#;
+
+        #[test_case("", "" ; "flake with no inputs")]
+        #[test_case(r#""flake":false,"#, "" ; "non-flake with no inputs")]
+        #[test_case("", r#""inputs":{"nixpkgs":"nixpkgs"},"# ; "flake with inputs")]
+        #[test_case(
+            r#""flake":false,"#,
+            r#""inputs":{"nixpkgs":["root","nixpkgs"]},"#
+            ; "non-flake with inputs"
+        )]
+        fn locked_node(flake: &str, inputs: &str) {
+            let json = format!("{{{flake}{inputs}{LOCKED},{ORIGINAL}}}");
+            let node = serde_json::from_str::<LockedNode>(&json).unwrap();
+            assert_eq!(node.flake, flake.is_empty());
+            assert_eq!(node.inputs.is_empty(), inputs.is_empty());
+            assert_eq!(serde_json::to_string(&node).unwrap(), json);
+        }
+
+        // `locked` and `original` are kept as JSON, so attributes that aren't
+        // part of the input scheme survive a round trip.
+        #[test]
+        fn original_extra_attrs() {
+            let json = concat!(
+                r#"{"locked":{"lastModified":1736200483,"owner":"NixOS","repo":"nixpkgs","#,
+                r#""rev":"3f0a8ac25fb674611b98089ca3a5dd6480175751","type":"github"},"#,
+                r#""original":{"dir":"lib","lastModified":1736200483,"#,
+                r#""narHash":"sha256-JO+lFN2HsCwSLMUWXHeOad6QUxOuwe9UOAF/iSl1J4I=","#,
+                r#""owner":"NixOS","ref":"nixos-unstable","repo":"nixpkgs","type":"github","#,
+                r#""unexpected":{"nested":[1,2]}}}"#
+            );
+            let node = serde_json::from_str::<LockedNode>(json).unwrap();
+            assert_eq!(node.original["unexpected"]["nested"][1], 2);
+            assert_eq!(serde_json::to_string(&node).unwrap(), json);
+        }
+
+        #[test_case(r#""__final":true,"# ; "final only")]
+        #[test_case(r#""__final":true,"dir":"sub","# ; "final with dir")]
+        fn locked_extra_attrs(extra: &str) {
+            let json = format!(r#"{{"locked":{{{extra}"rev":"abc","type":"github"}},{ORIGINAL}}}"#);
+            let node = serde_json::from_str::<LockedNode>(&json).unwrap();
+            assert_eq!(node.locked["__final"], true);
+            assert_eq!(serde_json::to_string(&node).unwrap(), json);
+        }
+
+        #[test]
+        fn explicit_flake_true_is_omitted() {
+            let json = format!(r#"{{"flake":true,{LOCKED},{ORIGINAL}}}"#);
+            let node = serde_json::from_str::<LockedNode>(&json).unwrap();
+            assert_eq!(
+                serde_json::to_string(&node).unwrap(),
+                format!("{{{LOCKED},{ORIGINAL}}}")
+            );
+        }
+
+        #[test_case(&format!("{{{ORIGINAL}}}") ; "missing locked")]
+        #[test_case(&format!("{{{LOCKED}}}") ; "missing original")]
+        fn locked_node_requires(json: &str) {
+            assert!(serde_json::from_str::<LockedNode>(json).is_err());
+        }
+
+        #[test_case("{}", "{}" ; "no inputs")]
+        #[test_case(r#"{"inputs":{}}"#, "{}" ; "empty inputs")]
+        #[test_case(
+            r#"{"inputs":{"nixpkgs":"nixpkgs"}}"#,
+            r#"{"inputs":{"nixpkgs":"nixpkgs"}}"#
+            ; "with inputs"
+        )]
+        fn unlocked_node(json: &str, expected: &str) {
+            let node = serde_json::from_str::<Node>(json).unwrap();
+            assert!(matches!(node, Node::Unlocked(_)));
+            assert_eq!(serde_json::to_string(&node).unwrap(), expected);
+        }
+    }
+}
@@ -1,15 +1,33 @@
-use data_encoding::{BASE64, DecodeError, DecodePartial, Encoding, HEXLOWER};
+use data_encoding::{
+    BASE64, BASE64_NOPAD, BASE64URL_NOPAD, DecodeError, DecodeKind, DecodePartial, Encoding,
+    HEXLOWER,
+};
 use data_encoding_macro::new_encoding;
-use strum::{EnumString, IntoStaticStr};
+use digest::{Digest, DynDigest};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use strum::{EnumCount, EnumString, IntoStaticStr};
+use subtle::ConstantTimeEq;
 
 const MAX_HASH_SIZE: usize = 64;
+const _: () = assert!(MAX_HASH_SIZE >= HashAlgo::max_size());
+#[cfg(not(feature = "extra-algos"))]
 const HASH_TYPES_LIST: &str = "`blake3`, `md5`, `sha1`, `sha256`, or `sha512`";
+#[cfg(feature = "extra-algos")]
+const HASH_TYPES_LIST: &str = "`blake3`, `md5`, `sha1`, `sha224`, `sha256`, `sha384`, or `sha512`";
+// Algorithms seen in SRI strings from the web that Nix can't check.
+#[cfg(not(feature = "extra-algos"))]
+const UNSUPPORTED_ALGOS: &[&str] = &["sha224", "sha384"];
+#[cfg(feature = "extra-algos")]
+const UNSUPPORTED_ALGOS: &[&str] = &[];
 
-// FIXME: Ensure that this matches the format of:
+// Nix reads the digest as a little-endian bit stream and prints the digits in
+// reverse, so this encoding must be paired with `encode_nix32`/`decode_nix32`.
 // <https://github.com/NixOS/nix/blob/c9211b0b2d52a26ed666780b763b39a5bddd3fb3/src/libutil/base-nix-32.cc>
-pub const BASE32NIX: Encoding = new_encoding! {
+pub(crate) const BASE32NIX: Encoding = new_encoding! {
     symbols: "0123456789abcdfghijklmnpqrsvwxyz",
+    bit_order: LeastSignificantFirst,
 };
+const MAX_NIX32_LEN: usize = (MAX_HASH_SIZE * 8).div_ceil(5);
 
 #[derive(Clone, Debug, Eq)]
 pub struct Hash {
@@ -19,17 +37,46 @@ pub struct Hash {
     show_algo: bool,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::Display, EnumString, IntoStaticStr)]
-#[strum(serialize_all = "lowercase")]
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    strum::Display,
+    EnumCount,
+    EnumString,
+    IntoStaticStr,
+)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+#[non_exhaustive]
 pub enum HashAlgo {
     Blake3,
     Md5,
     Sha1,
+    // Not understood by Nix, only here to check and print hashes from
+    // elsewhere, such as SRI strings on the web.
+    #[cfg(feature = "extra-algos")]
+    Sha224,
     Sha256,
+    #[cfg(feature = "extra-algos")]
+    Sha384,
     Sha512,
 }
 
+// The algorithm and digest of a `Hash`, without how it was written. It is
+// cheap to hash, since only the significant bytes of the digest are fed to
+// the hasher.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HashKey {
+    algo: HashAlgo,
+    bytes: [u8; MAX_HASH_SIZE],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, strum::Display)]
+#[strum(serialize_all = "lowercase")]
 pub enum HashFormat {
     Base64,
     Nix32,
@@ -37,12 +84,33 @@ pub enum HashFormat {
     Sri,
 }
 
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Md5(md5::Md5),
+    Sha1(sha1::Sha1),
+    #[cfg(feature = "extra-algos")]
+    Sha224(sha2::Sha224),
+    Sha256(sha2::Sha256),
+    #[cfg(feature = "extra-algos")]
+    Sha384(sha2::Sha384),
+    Sha512(sha2::Sha512),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MultiHasher {
+    hashers: Vec<Hasher>,
+}
+
 #[derive(Clone, Debug, PartialEq, thiserror::Error)]
 pub enum ParseError {
     #[error("hash does not specify a type, which is not otherwise known from context")]
     MissingPrefix,
     #[error("hash has an unknown prefix `{found}`, expected one of {HASH_TYPES_LIST}")]
     UnknownPrefix { found: String },
+    #[error("hash algorithm `{found}` is not supported, expected one of {HASH_TYPES_LIST}")]
+    UnsupportedAlgo { found: String },
     #[error("attempted to parse a hash of type `{want}`, found `{found}` instead")]
     ExpectedPrefix { want: HashAlgo, found: HashAlgo },
     #[error("hash of type `{algo}` with length `{n_chars}` does not match any encoding")]
@@ -51,6 +119,23 @@ pub enum ParseError {
     InvalidHash { algo: HashAlgo, n_bytes: usize },
     #[error("hash has an invalid encoding: {0}")]
     InvalidEncoding(#[from] DecodeError),
+    #[error("nix32 `{algo}` hash has bits set past the end of the digest")]
+    StrayBits { algo: HashAlgo },
+    #[error("hash contains whitespace")]
+    Whitespace,
+    #[error("hash is not in the expected `{want}` format")]
+    ExpectedFormat { want: HashFormat },
+    #[error("hash is not in SRI form, `<algo>-<base64>`")]
+    NotSri,
+    #[error("hash is not an RFC 6920 URI, `ni://<authority>/<algo>;<base64url>`")]
+    NotNi,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("requested an array of {want} bytes, but the hash has {found}")]
+pub struct SizeMismatch {
+    pub want: usize,
+    pub found: usize,
 }
 
 impl Hash {
@@ -63,6 +148,57 @@ impl Hash {
         }
     }
 
+    pub(crate) fn from_bytes(algo: HashAlgo, digest: &[u8]) -> Result<Self, ParseError> {
+        if digest.len() != algo.size() {
+            return Err(ParseError::InvalidHash {
+                algo,
+                n_bytes: digest.len(),
+            });
+        }
+        let mut bytes = [0; MAX_HASH_SIZE];
+        bytes[..digest.len()].copy_from_slice(digest);
+        Ok(Self {
+            algo,
+            bytes,
+            format: None,
+            show_algo: false,
+        })
+    }
+
+    pub fn from_digest<D: Digest>(algo: HashAlgo, digest: D) -> Result<Self, ParseError> {
+        Self::from_bytes(algo, digest.finalize().as_slice())
+    }
+
+    pub fn compute(algo: HashAlgo, data: impl AsRef<[u8]>) -> Self {
+        let mut hasher = Hasher::new(algo);
+        hasher.update(data.as_ref());
+        hasher.finalize()
+    }
+
+    // Hashes the `algo:base16` form, which is how store path descriptors
+    // embed an inner hash.
+    pub fn of_hash(algo: HashAlgo, other: &Hash) -> Self {
+        Self::compute(algo, other.to_string(&HashFormat::Base16, true))
+    }
+
+    #[cfg(feature = "smol")]
+    pub async fn compute_file(
+        algo: HashAlgo,
+        path: impl AsRef<std::path::Path>,
+    ) -> std::io::Result<Self> {
+        use smol::io::AsyncReadExt;
+
+        let mut file = smol::fs::File::open(path).await?;
+        let mut hasher = Hasher::new(algo);
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match file.read(&mut buf).await? {
+                0 => break Ok(hasher.finalize()),
+                n => hasher.update(&buf[..n]),
+            }
+        }
+    }
+
     pub fn algorithm(&self) -> HashAlgo {
         self.algo
     }
@@ -71,10 +207,32 @@ impl Hash {
         &self.bytes[..self.algo.size()]
     }
 
+    pub fn try_into_array<const N: usize>(&self) -> Result<[u8; N], SizeMismatch> {
+        self.bytes().try_into().map_err(|_| SizeMismatch {
+            want: N,
+            found: self.algo.size(),
+        })
+    }
+
     pub fn format(&self) -> Option<HashFormat> {
         self.format
     }
 
+    // Unlike `Ord`, this also orders by how the hash was written, so that the
+    // same digest in different encodings is still sorted the same every time.
+    pub fn csv_sort_key(&self) -> impl Ord + '_ {
+        (self.algo, self.bytes(), self.format, self.show_algo)
+    }
+
+    pub fn key(&self) -> HashKey {
+        let mut bytes = [0; MAX_HASH_SIZE];
+        bytes[..self.algo.size()].copy_from_slice(self.bytes());
+        HashKey {
+            algo: self.algo,
+            bytes,
+        }
+    }
+
     pub fn to_string(&self, format: &HashFormat, show_algo: bool) -> String {
         let mut buf = String::with_capacity(match format {
             HashFormat::Base64 | HashFormat::Sri => BASE64.encode_len(self.algo.size()),
@@ -85,6 +243,15 @@ impl Hash {
         buf
     }
 
+    pub fn into_string(self, format: &HashFormat, show_algo: bool) -> String {
+        self.to_string(format, show_algo)
+    }
+
+    // Same as `nix hash convert --to nix32`, without the algorithm prefix.
+    pub fn to_nix_base32(&self) -> String {
+        self.to_string(&HashFormat::Nix32, false)
+    }
+
     pub(crate) fn encode(
         &self,
         format: &HashFormat,
@@ -98,7 +265,7 @@ impl Hash {
         }
         match format {
             HashFormat::Base64 | HashFormat::Sri => BASE64.encode_write(self.bytes(), &mut buf)?,
-            HashFormat::Nix32 => BASE32NIX.encode_write(self.bytes(), &mut buf)?,
+            HashFormat::Nix32 => encode_nix32(self.bytes(), &mut buf)?,
             HashFormat::Base16 => HEXLOWER.encode_write(self.bytes(), &mut buf)?,
         }
         Ok(())
@@ -112,6 +279,105 @@ impl Hash {
         Self::parse_(input, Some(algo))
     }
 
+    // For callers that already know how the hash is encoded, so its length
+    // only has to match that encoding.
+    pub fn parse_with_format(
+        input: &str,
+        algo: HashAlgo,
+        format: HashFormat,
+    ) -> Result<Self, ParseError> {
+        let (algo_prefix, is_sri, hash) = Self::parse_prefix(input)?;
+        if let Some(found) = algo_prefix.filter(|&found| found != algo) {
+            return Err(ParseError::ExpectedPrefix { want: algo, found });
+        }
+        if is_sri != (format == HashFormat::Sri) {
+            return Err(ParseError::ExpectedFormat { want: format });
+        }
+        let mut decoded = Self::decode_as(hash, algo, format)?;
+        decoded.show_algo = algo_prefix.is_some();
+        Ok(decoded)
+    }
+
+    // For tables that keep the algorithm in a column of its own, such as the
+    // one written by `nixpkgs-hashes`. No encoding has `:`, `-` or whitespace
+    // among its symbols, so a hash that decodes as it is can't have a prefix,
+    // and only the ones that don't go through `parse_prefix`. A prefix that
+    // is the same as the column, as in SRI rows, is stripped instead of being
+    // parsed again.
+    pub fn parse_csv_record(hash: &str, algo_name: &str) -> Result<Self, ParseError> {
+        let algo = parse_algo(algo_name)?;
+        let decoded = match hash
+            .strip_prefix(algo_name)
+            .and_then(|rest| rest.split_at_checked(1))
+        {
+            Some(("-", sri)) => Self::decode(sri, algo, true),
+            Some((":", rest)) => Self::decode(rest, algo, false),
+            _ => return Self::decode(hash, algo, false).or_else(|_| Self::parse_as(hash, algo)),
+        };
+        decoded
+            .map(|mut decoded| {
+                decoded.show_algo = true;
+                decoded
+            })
+            .or_else(|_| Self::parse_as(hash, algo))
+    }
+
+    // Only accepts padded SRI, the form Nix prints, so that untrusted input
+    // can't pick a different or ambiguous encoding.
+    pub fn parse_sri_strict(input: &str) -> Result<Self, ParseError> {
+        let (algo, is_sri, hash) = Self::parse_prefix(input)?;
+        let Some(algo) = algo.filter(|_| is_sri) else {
+            return Err(ParseError::NotSri);
+        };
+        if hash.len() != BASE64.encode_len(algo.size()) {
+            let n_chars = hash.len();
+            return Err(ParseError::WrongLength { algo, n_chars });
+        }
+        let mut decoded = Self::decode_as(hash, algo, HashFormat::Sri)?;
+        decoded.show_algo = true;
+        Ok(decoded)
+    }
+
+    // An RFC 6920 named information URI. The authority and any query are
+    // ignored. Truncated digests such as `sha-256-128` are not supported.
+    // <https://www.rfc-editor.org/rfc/rfc6920>
+    pub fn parse_ni(uri: &str) -> Result<Self, ParseError> {
+        let rest = uri
+            .get(..5)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("ni://"))
+            .map(|_| &uri[5..])
+            .ok_or(ParseError::NotNi)?;
+        let (_authority, path) = rest.split_once('/').ok_or(ParseError::NotNi)?;
+        let path = path.split_once('?').map_or(path, |(path, _query)| path);
+        let (name, digest) = path.split_once(';').ok_or(ParseError::NotNi)?;
+        let algo = match name {
+            "sha-256" => HashAlgo::Sha256,
+            #[cfg(feature = "extra-algos")]
+            "sha-384" => HashAlgo::Sha384,
+            "sha-512" => HashAlgo::Sha512,
+            _ => {
+                let found = name.to_string();
+                return Err(ParseError::UnsupportedAlgo { found });
+            }
+        };
+        Self::from_bytes(algo, &BASE64URL_NOPAD.decode(digest.as_bytes())?)
+    }
+
+    pub fn detect_format(input: &str, algo: HashAlgo) -> Result<HashFormat, ParseError> {
+        let parsed = Self::parse_as(input, algo)?;
+        Ok(parsed.format.expect("decoded hashes record their format"))
+    }
+
+    pub fn matches_input(&self, input: &str) -> bool {
+        Self::parse_as(input, self.algo).is_ok_and(|parsed| parsed.ct_eq(self))
+    }
+
+    // Like `==`, but the digests are compared in constant time so that
+    // checking untrusted input doesn't leak how much of it matched.
+    pub fn ct_eq(&self, other: &Self) -> bool {
+        self.algo == other.algo && bool::from(self.bytes().ct_eq(other.bytes()))
+    }
+
     pub(crate) fn parse_(input: &str, algo: Option<HashAlgo>) -> Result<Self, ParseError> {
         let (algo_prefix, is_sri, hash) = Self::parse_prefix(input)?;
         let algo = match (algo, algo_prefix) {
@@ -131,88 +397,496 @@ impl Hash {
     }
 
     pub(crate) fn parse_prefix(input: &str) -> Result<(Option<HashAlgo>, bool, &str), ParseError> {
+        // Hashes read from command output usually end with a newline.
+        let input = input.trim_ascii();
+        if input.contains(|c: char| c.is_ascii_whitespace()) {
+            return Err(ParseError::Whitespace);
+        }
         let (prefix, is_sri, hash);
+        // URL-safe base64 can contain `-`, so it only separates an SRI prefix
+        // when preceded by a known algorithm name.
+        let sri = input.split_once('-').filter(|(prefix, _)| {
+            prefix.parse::<HashAlgo>().is_ok() || is_unsupported_algo(prefix)
+        });
         if let Some(pair) = input.split_once(':') {
             (prefix, hash) = (Some(pair.0), pair.1);
             is_sri = false;
-        } else if let Some(pair) = input.split_once('-') {
+        } else if let Some(pair) = sri {
             (prefix, hash) = (Some(pair.0), pair.1);
             is_sri = true;
         } else {
             (prefix, hash) = (None, input);
             is_sri = false;
         }
-        let algo = prefix
-            .map(|prefix| {
-                prefix.parse().map_err(|_| ParseError::UnknownPrefix {
-                    found: prefix.to_string(),
-                })
-            })
-            .transpose()?;
+        let algo = prefix.map(parse_algo).transpose()?;
         Ok((algo, is_sri, hash))
     }
 
     pub(crate) fn decode(hash: &str, algo: HashAlgo, is_sri: bool) -> Result<Self, ParseError> {
+        let n_chars = hash.len();
+        let format = if is_sri {
+            HashFormat::Sri
+        } else if n_chars == HEXLOWER.encode_len(algo.size()) {
+            HashFormat::Base16
+        } else if n_chars == BASE32NIX.encode_len(algo.size()) {
+            HashFormat::Nix32
+        } else if n_chars == BASE64.encode_len(algo.size())
+            || n_chars == BASE64_NOPAD.encode_len(algo.size())
+        {
+            HashFormat::Base64
+        } else {
+            return Err(ParseError::WrongLength { algo, n_chars });
+        };
+        Self::decode_as(hash, algo, format)
+    }
+
+    fn decode_as(hash: &str, algo: HashAlgo, format: HashFormat) -> Result<Self, ParseError> {
         let hash = hash.as_bytes();
-        if !is_sri && hash.len() == HEXLOWER.encode_len(algo.size()) {
-            let mut bytes = [0; MAX_HASH_SIZE];
-            HEXLOWER.decode_mut(hash, &mut bytes[..algo.size()])?;
-            Ok(Self::_new(algo, bytes, HashFormat::Base16))
-        } else if !is_sri && hash.len() == BASE32NIX.encode_len(algo.size()) {
-            let mut bytes = [0; MAX_HASH_SIZE];
-            BASE32NIX.decode_mut(hash, &mut bytes[..algo.size()])?;
-            Ok(Self::_new(algo, bytes, HashFormat::Nix32))
-        } else if is_sri || hash.len() == BASE64.encode_len(algo.size()) {
-            let mut buf = [0; MAX_HASH_SIZE + 2];
-            let wrote = BASE64.decode_mut(hash, &mut buf[..BASE64.decode_len(hash.len())?])?;
-            if wrote == algo.size() {
-                let mut bytes = [0; MAX_HASH_SIZE];
-                bytes[..wrote].copy_from_slice(&buf[..wrote]);
-                let format = if is_sri {
-                    HashFormat::Sri
+        let wrong_length = ParseError::WrongLength {
+            algo,
+            n_chars: hash.len(),
+        };
+        let mut bytes = [0; MAX_HASH_SIZE];
+        match format {
+            HashFormat::Base16 => {
+                if hash.len() != HEXLOWER.encode_len(algo.size()) {
+                    return Err(wrong_length);
+                }
+                HEXLOWER.decode_mut(hash, &mut bytes[..algo.size()])?;
+            }
+            HashFormat::Nix32 => {
+                if hash.len() != BASE32NIX.encode_len(algo.size()) {
+                    return Err(wrong_length);
+                }
+                // Nix rejects a leading digit that sets bits beyond the digest.
+                decode_nix32(hash, &mut bytes[..algo.size()]).map_err(|e| match e.error.kind {
+                    DecodeKind::Trailing => ParseError::StrayBits { algo },
+                    _ => e.into(),
+                })?;
+            }
+            HashFormat::Base64 | HashFormat::Sri => {
+                // Nix tolerates missing padding, which is distinguishable by length.
+                let base64 = if hash.len().is_multiple_of(4) {
+                    &BASE64
                 } else {
-                    HashFormat::Base64
+                    &BASE64_NOPAD
                 };
-                Ok(Self::_new(algo, bytes, format))
-            } else {
-                Err(ParseError::InvalidHash {
-                    algo,
-                    n_bytes: wrote,
-                })
+                let mut buf = [0; MAX_HASH_SIZE + 2];
+                let decode_len = base64.decode_len(hash.len())?;
+                if decode_len > buf.len() {
+                    return Err(wrong_length);
+                }
+                let wrote = base64.decode_mut(hash, &mut buf[..decode_len])?;
+                if wrote != algo.size() {
+                    return Err(ParseError::InvalidHash {
+                        algo,
+                        n_bytes: wrote,
+                    });
+                }
+                bytes[..wrote].copy_from_slice(&buf[..wrote]);
             }
-        } else {
-            Err(ParseError::WrongLength {
-                algo,
-                n_chars: hash.len(),
-            })
         }
+        Ok(Self::_new(algo, bytes, format))
     }
 }
 
+fn parse_algo(name: &str) -> Result<HashAlgo, ParseError> {
+    name.parse().map_err(|_| {
+        let found = name.to_string();
+        if is_unsupported_algo(name) {
+            ParseError::UnsupportedAlgo { found }
+        } else {
+            ParseError::UnknownPrefix { found }
+        }
+    })
+}
+
+fn is_unsupported_algo(name: &str) -> bool {
+    UNSUPPORTED_ALGOS
+        .iter()
+        .any(|algo| algo.eq_ignore_ascii_case(name))
+}
+
+pub(crate) fn encode_nix32(bytes: &[u8], mut buf: impl std::fmt::Write) -> std::fmt::Result {
+    let mut encoded = [0; MAX_NIX32_LEN];
+    let encoded = &mut encoded[..BASE32NIX.encode_len(bytes.len())];
+    BASE32NIX.encode_mut(bytes, encoded);
+    encoded.reverse();
+    encoded
+        .iter()
+        .try_for_each(|&symbol| buf.write_char(symbol as char))
+}
+
+pub(crate) fn decode_nix32(input: &[u8], output: &mut [u8]) -> Result<usize, DecodePartial> {
+    let mut reversed = [0; MAX_NIX32_LEN];
+    let reversed = &mut reversed[..input.len()];
+    reversed.copy_from_slice(input);
+    reversed.reverse();
+    BASE32NIX.decode_mut(reversed, output).map_err(|mut e| {
+        e.error.position = input.len() - 1 - e.error.position;
+        e
+    })
+}
+
 impl PartialEq for Hash {
     fn eq(&self, other: &Self) -> bool {
         self.algo == other.algo && self.bytes() == other.bytes()
     }
 }
 
+// Orders by algorithm, in the alphabetical order of their names, and then by
+// digest.
+impl Ord for Hash {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.algo, self.bytes()).cmp(&(other.algo, other.bytes()))
+    }
+}
+
+impl PartialOrd for Hash {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl HashKey {
+    pub fn algorithm(&self) -> HashAlgo {
+        self.algo
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.algo.size()]
+    }
+}
+
+impl std::hash::Hash for HashKey {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        state.write_u8(self.algo as u8);
+        state.write(self.bytes());
+    }
+}
+
+impl From<&Hash> for HashKey {
+    fn from(hash: &Hash) -> Self {
+        hash.key()
+    }
+}
+
+// The alternate form, `{:#}`, is `<algo>:<nix32>`, as hashes are written in
+// derivations and older lock files.
 impl std::fmt::Display for Hash {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if f.alternate() {
+            return self.encode(&HashFormat::Nix32, true, f);
+        }
         self.encode(&self.format.unwrap_or(HashFormat::Sri), self.show_algo, f)
     }
 }
 
+impl From<Hash> for String {
+    fn from(hash: Hash) -> Self {
+        ToString::to_string(&hash)
+    }
+}
+
+// Unlike `Display`, the algorithm is always written, since `Deserialize` has
+// no other way of knowing it.
+impl Serialize for Hash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let format = self.format.unwrap_or(HashFormat::Sri);
+        serializer.serialize_str(&self.to_string(&format, true))
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let input = String::deserialize(deserializer)?;
+        Hash::parse(&input).map_err(serde::de::Error::custom)
+    }
+}
+
+// Modules for `#[serde(with = "...")]` that pin the serialized format of a `Hash`.
+pub mod format {
+    macro_rules! serde_format {
+        ($name:ident, $format:expr) => {
+            pub mod $name {
+                use serde::{Deserialize, Deserializer, Serializer};
+
+                use crate::hash::{Hash, HashFormat};
+
+                pub fn serialize<S: Serializer>(
+                    hash: &Hash,
+                    serializer: S,
+                ) -> Result<S::Ok, S::Error> {
+                    serializer.serialize_str(&hash.to_string(&$format, true))
+                }
+
+                pub fn deserialize<'de, D: Deserializer<'de>>(
+                    deserializer: D,
+                ) -> Result<Hash, D::Error> {
+                    Hash::deserialize(deserializer)
+                }
+            }
+        };
+    }
+
+    serde_format!(base16, HashFormat::Base16);
+    serde_format!(nix32, HashFormat::Nix32);
+    serde_format!(base64, HashFormat::Base64);
+    serde_format!(sri, HashFormat::Sri);
+}
+
+// Strategies for generating hashes and hash strings with `proptest`.
+#[cfg(feature = "proptest")]
+pub mod strategy {
+    use proptest::prelude::*;
+    use proptest::sample::select;
+
+    use super::{Hash, HashAlgo, HashFormat};
+
+    pub fn algo() -> impl Strategy<Value = HashAlgo> {
+        select(HashAlgo::all())
+    }
+
+    pub fn format() -> impl Strategy<Value = HashFormat> {
+        select(
+            &[
+                HashFormat::Base64,
+                HashFormat::Nix32,
+                HashFormat::Base16,
+                HashFormat::Sri,
+            ][..],
+        )
+    }
+
+    pub fn hash() -> impl Strategy<Value = Hash> {
+        algo().prop_flat_map(|algo| {
+            proptest::collection::vec(any::<u8>(), algo.size())
+                .prop_map(move |bytes| Hash::from_bytes(algo, &bytes).unwrap())
+        })
+    }
+
+    // A hash along with one of its valid string representations.
+    pub fn hash_string() -> impl Strategy<Value = (Hash, String)> {
+        (hash(), format(), any::<bool>()).prop_map(|(hash, format, show_algo)| {
+            let string = hash.to_string(&format, show_algo);
+            (hash, string)
+        })
+    }
+
+    // A valid hash string with its last character removed or one appended.
+    pub fn near_miss_string() -> impl Strategy<Value = (Hash, String)> {
+        (
+            hash_string(),
+            any::<bool>(),
+            select(&['0', 'a', 'z', '=', '+'][..]),
+        )
+            .prop_map(|((hash, mut string), truncate, extra)| {
+                if truncate {
+                    string.pop();
+                } else {
+                    string.push(extra);
+                }
+                (hash, string)
+            })
+    }
+}
+
 impl HashAlgo {
+    pub const fn all() -> &'static [HashAlgo] {
+        &[
+            HashAlgo::Blake3,
+            HashAlgo::Md5,
+            HashAlgo::Sha1,
+            #[cfg(feature = "extra-algos")]
+            HashAlgo::Sha224,
+            HashAlgo::Sha256,
+            #[cfg(feature = "extra-algos")]
+            HashAlgo::Sha384,
+            HashAlgo::Sha512,
+        ]
+    }
+
+    pub const fn max_size() -> usize {
+        let (all, mut i, mut max) = (Self::all(), 0, 0);
+        while i < all.len() {
+            if all[i].size() > max {
+                max = all[i].size();
+            }
+            i += 1;
+        }
+        max
+    }
+
+    // BLAKE3 and SHA-256 are both 256-bit and rank equally. Between the two,
+    // `strongest` picks SHA-256, since it is what Nix defaults to and every
+    // version of Nix understands it. SHA-224 and SHA-384 share the rank of
+    // SHA-256 and SHA-512, so that the ranks don't depend on `extra-algos`,
+    // and `strongest` prefers the longer digest within a rank.
+    pub const fn strength(&self) -> u8 {
+        match self {
+            HashAlgo::Md5 => 0,
+            HashAlgo::Sha1 => 1,
+            HashAlgo::Blake3 | HashAlgo::Sha256 => 2,
+            #[cfg(feature = "extra-algos")]
+            HashAlgo::Sha224 => 2,
+            HashAlgo::Sha512 => 3,
+            #[cfg(feature = "extra-algos")]
+            HashAlgo::Sha384 => 3,
+        }
+    }
+
+    pub fn strongest(algos: impl IntoIterator<Item = HashAlgo>) -> Option<HashAlgo> {
+        algos
+            .into_iter()
+            .max_by_key(|algo| (algo.strength(), algo.size(), *algo == HashAlgo::Sha256))
+    }
+
+    pub fn try_from_size(size: usize) -> Vec<HashAlgo> {
+        Self::all()
+            .iter()
+            .copied()
+            .filter(|algo| algo.size() == size)
+            .collect()
+    }
+
+    pub fn digest_new(&self) -> Box<dyn DynDigest> {
+        match self {
+            HashAlgo::Blake3 => Box::new(blake3::Hasher::new()),
+            HashAlgo::Md5 => Box::new(md5::Md5::new()),
+            HashAlgo::Sha1 => Box::new(sha1::Sha1::new()),
+            #[cfg(feature = "extra-algos")]
+            HashAlgo::Sha224 => Box::new(sha2::Sha224::new()),
+            HashAlgo::Sha256 => Box::new(sha2::Sha256::new()),
+            #[cfg(feature = "extra-algos")]
+            HashAlgo::Sha384 => Box::new(sha2::Sha384::new()),
+            HashAlgo::Sha512 => Box::new(sha2::Sha512::new()),
+        }
+    }
+
     pub const fn size(&self) -> usize {
         match self {
             HashAlgo::Blake3 => 32,
             HashAlgo::Md5 => 16,
             HashAlgo::Sha1 => 20,
+            #[cfg(feature = "extra-algos")]
+            HashAlgo::Sha224 => 28,
             HashAlgo::Sha256 => 32,
+            #[cfg(feature = "extra-algos")]
+            HashAlgo::Sha384 => 48,
             HashAlgo::Sha512 => 64,
         }
     }
 }
 
+impl Hasher {
+    pub fn new(algo: HashAlgo) -> Self {
+        match algo {
+            HashAlgo::Blake3 => Self::Blake3(Box::default()),
+            HashAlgo::Md5 => Self::Md5(md5::Md5::new()),
+            HashAlgo::Sha1 => Self::Sha1(sha1::Sha1::new()),
+            #[cfg(feature = "extra-algos")]
+            HashAlgo::Sha224 => Self::Sha224(sha2::Sha224::new()),
+            HashAlgo::Sha256 => Self::Sha256(sha2::Sha256::new()),
+            #[cfg(feature = "extra-algos")]
+            HashAlgo::Sha384 => Self::Sha384(sha2::Sha384::new()),
+            HashAlgo::Sha512 => Self::Sha512(sha2::Sha512::new()),
+        }
+    }
+
+    pub fn algorithm(&self) -> HashAlgo {
+        match self {
+            Self::Blake3(_) => HashAlgo::Blake3,
+            Self::Md5(_) => HashAlgo::Md5,
+            Self::Sha1(_) => HashAlgo::Sha1,
+            #[cfg(feature = "extra-algos")]
+            Self::Sha224(_) => HashAlgo::Sha224,
+            Self::Sha256(_) => HashAlgo::Sha256,
+            #[cfg(feature = "extra-algos")]
+            Self::Sha384(_) => HashAlgo::Sha384,
+            Self::Sha512(_) => HashAlgo::Sha512,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Blake3(hasher) => Digest::update(&mut **hasher, data),
+            Self::Md5(hasher) => Digest::update(hasher, data),
+            Self::Sha1(hasher) => Digest::update(hasher, data),
+            #[cfg(feature = "extra-algos")]
+            Self::Sha224(hasher) => Digest::update(hasher, data),
+            Self::Sha256(hasher) => Digest::update(hasher, data),
+            #[cfg(feature = "extra-algos")]
+            Self::Sha384(hasher) => Digest::update(hasher, data),
+            Self::Sha512(hasher) => Digest::update(hasher, data),
+        }
+    }
+
+    pub fn finalize(self) -> Hash {
+        let algo = self.algorithm();
+        match self {
+            Self::Blake3(hasher) => Hash::from_digest(algo, *hasher),
+            Self::Md5(hasher) => Hash::from_digest(algo, hasher),
+            Self::Sha1(hasher) => Hash::from_digest(algo, hasher),
+            #[cfg(feature = "extra-algos")]
+            Self::Sha224(hasher) => Hash::from_digest(algo, hasher),
+            Self::Sha256(hasher) => Hash::from_digest(algo, hasher),
+            #[cfg(feature = "extra-algos")]
+            Self::Sha384(hasher) => Hash::from_digest(algo, hasher),
+            Self::Sha512(hasher) => Hash::from_digest(algo, hasher),
+        }
+        .unwrap()
+    }
+}
+
+impl std::io::Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl MultiHasher {
+    // Repeated algorithms are only hashed once.
+    pub fn new(algos: impl IntoIterator<Item = HashAlgo>) -> Self {
+        let mut hashers = Vec::<Hasher>::new();
+        for algo in algos {
+            if !hashers.iter().any(|hasher| hasher.algorithm() == algo) {
+                hashers.push(Hasher::new(algo));
+            }
+        }
+        Self { hashers }
+    }
+
+    pub fn algorithms(&self) -> impl Iterator<Item = HashAlgo> + '_ {
+        self.hashers.iter().map(Hasher::algorithm)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for hasher in &mut self.hashers {
+            hasher.update(data);
+        }
+    }
+
+    pub fn finalize(self) -> Vec<Hash> {
+        self.hashers.into_iter().map(Hasher::finalize).collect()
+    }
+}
+
+impl std::io::Write for MultiHasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
 impl From<DecodePartial> for ParseError {
     fn from(other: DecodePartial) -> Self {
         other.error.into()
@@ -221,10 +895,14 @@ impl From<DecodePartial> for ParseError {
 
 #[cfg(test)]
 mod tests {
+    use data_encoding::BASE64URL_NOPAD;
     use digest::Digest;
     use test_case::{test_case, test_matrix};
 
-    use super::{Hash, HashAlgo, HashFormat, MAX_HASH_SIZE, ParseError};
+    use super::{
+        Hash, HashAlgo, HashFormat, HashKey, Hasher, MAX_HASH_SIZE, MultiHasher, ParseError,
+        SizeMismatch,
+    };
 
     fn hash_string(s: &str, algo: HashAlgo) -> Hash {
         let mut bytes = [0; MAX_HASH_SIZE];
@@ -233,7 +911,11 @@ mod tests {
             HashAlgo::Blake3 => buf.copy_from_slice(blake3::Hasher::digest(s).as_slice()),
             HashAlgo::Md5 => buf.copy_from_slice(md5::Md5::digest(s).as_slice()),
             HashAlgo::Sha1 => buf.copy_from_slice(sha1::Sha1::digest(s).as_slice()),
+            #[cfg(feature = "extra-algos")]
+            HashAlgo::Sha224 => buf.copy_from_slice(sha2::Sha224::digest(s).as_slice()),
             HashAlgo::Sha256 => buf.copy_from_slice(sha2::Sha256::digest(s).as_slice()),
+            #[cfg(feature = "extra-algos")]
+            HashAlgo::Sha384 => buf.copy_from_slice(sha2::Sha384::digest(s).as_slice()),
             HashAlgo::Sha512 => buf.copy_from_slice(sha2::Sha512::digest(s).as_slice()),
         };
         Hash {
@@ -285,6 +967,14 @@ mod tests {
         "abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq", HashAlgo::Sha256
         => "sha256:248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
     )]
+    #[cfg_attr(feature = "extra-algos", test_case(
+        "abc", HashAlgo::Sha224
+        => "sha224:23097d223405d8228642a477bda255b32aadbce4bda0b3f7e36c9da7"
+    ))]
+    #[cfg_attr(feature = "extra-algos", test_case(
+        "abc", HashAlgo::Sha384
+        => "sha384:cb00753f45a35e8bb5a03d699ac65007272c32ab0eded1631a8b605a43ff5bed8086072ba1e7cc2358baeca134c825a7"
+    ))]
     #[test_case(
         "abc", HashAlgo::Sha512
         => "sha512:ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
@@ -299,6 +989,858 @@ mod tests {
         ))
     }
 
+    // the digest of empty input as Nix prints it, e.g. `nix-hash --type sha256 --flat --base32 /dev/null`
+    #[test_case(
+        "", HashAlgo::Sha256
+        => "sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"
+    )]
+    #[test_case("", HashAlgo::Md5 => "md5:3y8bwfr609h3lh9ch0izcqq7fl")]
+    #[test_case("", HashAlgo::Sha1 => "sha1:143xibwh31h9bvxzalr0sjvbbvpa6ffs")]
+    #[test_case(
+        "", HashAlgo::Sha512
+        => "sha512:0zdl9zrg8r3i9c1g90lgg9ip5ijzv3yhz91i0zzn3r8ap9ws784gkp9dk9j3aglhgf1amqb0pj21mh7h1nxcl18akqvvf7ggqsy30yg"
+    )]
+    #[test_case(
+        "hello", HashAlgo::Sha256
+        => "sha256:094qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwic"
+    )]
+    fn assert_known_nix32(s: &str, algo: HashAlgo) -> String {
+        let hash = hash_string(s, algo);
+        assert_eq!(
+            hash.to_nix_base32(),
+            hash.to_string(&HashFormat::Nix32, false)
+        );
+        hash.to_string(&HashFormat::Nix32, true)
+    }
+
+    // Checks against the `nix` on `PATH`, when there is one.
+    #[cfg(feature = "nix-cli")]
+    #[test]
+    fn nix_cli_base32() {
+        use std::process::Command;
+
+        // BLAKE3 is still behind an experimental feature in Nix.
+        let algos = HashAlgo::all()
+            .iter()
+            .filter(|&&algo| algo != HashAlgo::Blake3);
+        for &algo in algos {
+            for s in ["", "hello", "Rust is okay, but C++ is a blight."] {
+                let hash = hash_string(s, algo);
+                let output = Command::new("nix")
+                    .args(["--extra-experimental-features", "nix-command"])
+                    .args(["hash", "convert", "--to", "nix32"])
+                    .arg(hash.to_string(&HashFormat::Sri, true))
+                    .output();
+                let output = match output {
+                    Ok(output) => output,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        eprintln!("skipping, `nix` is not on PATH");
+                        return;
+                    }
+                    Err(e) => panic!("failed to run `nix`: {e}"),
+                };
+                assert!(
+                    output.status.success(),
+                    "{}",
+                    String::from_utf8_lossy(&output.stderr)
+                );
+                let stdout = String::from_utf8(output.stdout).unwrap();
+                let expect = stdout.trim();
+                let expect = expect.split_once(':').map_or(expect, |(_, hash)| hash);
+                assert_eq!(hash.to_nix_base32(), expect, "{algo} of {s:?}");
+            }
+        }
+    }
+
+    #[test_case(HashAlgo::Blake3)]
+    #[test_case(HashAlgo::Md5)]
+    #[test_case(HashAlgo::Sha1)]
+    #[test_case(HashAlgo::Sha256)]
+    #[test_case(HashAlgo::Sha512)]
+    fn hasher(algo: HashAlgo) {
+        static S: &str = "Rust is okay, but C++ is a blight.";
+        let mut hasher = Hasher::new(algo);
+        for chunk in S.as_bytes().chunks(7) {
+            hasher.update(chunk);
+        }
+        assert_eq!(hasher.finalize(), hash_string(S, algo));
+        assert_eq!(Hash::compute(algo, S.as_bytes()), hash_string(S, algo));
+    }
+
+    #[test_case(HashFormat::Base16, true)]
+    #[test_case(HashFormat::Base16, false)]
+    #[test_case(HashFormat::Nix32, true)]
+    #[test_case(HashFormat::Nix32, false)]
+    #[test_case(HashFormat::Base64, true)]
+    #[test_case(HashFormat::Base64, false)]
+    #[test_case(HashFormat::Sri, true)]
+    fn detect_format(format: HashFormat, show_algo: bool) {
+        for &algo in HashAlgo::all() {
+            let input = Hash::compute(algo, b"").to_string(&format, show_algo);
+            assert_eq!(Hash::detect_format(&input, algo), Ok(format));
+        }
+    }
+
+    #[test]
+    fn detect_format_invalid() {
+        let unpadded = "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU";
+        assert_eq!(
+            Hash::detect_format(unpadded, HashAlgo::Sha256),
+            Ok(HashFormat::Base64)
+        );
+        assert!(matches!(
+            Hash::detect_format("aaaa", HashAlgo::Sha256),
+            Err(ParseError::WrongLength { .. })
+        ));
+        assert!(matches!(
+            Hash::detect_format(&format!("sha1:{unpadded}"), HashAlgo::Sha256),
+            Err(ParseError::ExpectedPrefix { .. })
+        ));
+    }
+
+    #[test]
+    fn multi_hasher() {
+        static S: &str = "Rust is okay, but C++ is a blight.";
+        let algos = [HashAlgo::Sha256, HashAlgo::Blake3, HashAlgo::Sha256];
+        let mut hasher = MultiHasher::new(algos);
+        assert_eq!(
+            hasher.algorithms().collect::<Vec<_>>(),
+            [HashAlgo::Sha256, HashAlgo::Blake3]
+        );
+        std::io::copy(&mut S.as_bytes(), &mut hasher).unwrap();
+        assert_eq!(
+            hasher.finalize(),
+            [
+                Hash::compute(HashAlgo::Sha256, S),
+                Hash::compute(HashAlgo::Blake3, S),
+            ]
+        );
+        assert!(MultiHasher::new([]).finalize().is_empty());
+    }
+
+    #[test_case(HashAlgo::Blake3)]
+    #[test_case(HashAlgo::Md5)]
+    #[test_case(HashAlgo::Sha1)]
+    #[test_case(HashAlgo::Sha256)]
+    #[test_case(HashAlgo::Sha512)]
+    fn digest_new(algo: HashAlgo) {
+        static S: &str = "Rust is okay, but C++ is a blight.";
+        let mut digest = algo.digest_new();
+        for chunk in S.as_bytes().chunks(7) {
+            digest.update(chunk);
+        }
+        let hash = Hash::from_bytes(algo, &digest.finalize()).unwrap();
+        assert_eq!(hash, Hash::compute(algo, S.as_bytes()));
+    }
+
+    #[test_case(HashAlgo::Sha256)]
+    #[test_case(HashAlgo::Sha512)]
+    fn oversized_sri(algo: HashAlgo) {
+        let hash = "A".repeat(4 * MAX_HASH_SIZE);
+        assert_eq!(
+            Hash::parse(&format!("{algo}-{hash}")),
+            Err(ParseError::WrongLength {
+                algo,
+                n_chars: hash.len()
+            })
+        );
+    }
+
+    #[test_case(102)]
+    #[test_case(104)]
+    fn sha512_nix32_off_by_one(n_chars: usize) {
+        let valid = Hash::compute(HashAlgo::Sha512, b"").to_nix_base32();
+        assert_eq!(valid.len(), 103);
+        let hash = if n_chars < valid.len() {
+            valid[..n_chars].to_string()
+        } else {
+            format!("{valid}{}", "0".repeat(n_chars - valid.len()))
+        };
+        assert_eq!(
+            Hash::parse_as(&hash, HashAlgo::Sha512),
+            Err(ParseError::WrongLength {
+                algo: HashAlgo::Sha512,
+                n_chars
+            })
+        );
+    }
+
+    #[test]
+    fn sha512_lengths() {
+        let hash = Hash::compute(HashAlgo::Sha512, b"");
+        for (format, n_chars) in [
+            (HashFormat::Nix32, 103),
+            (HashFormat::Base16, 128),
+            (HashFormat::Base64, 88),
+        ] {
+            let input = hash.to_string(&format, false);
+            assert_eq!(input.len(), n_chars);
+            let parsed = Hash::parse_as(&input, HashAlgo::Sha512).unwrap();
+            assert_eq!(parsed.format(), Some(format));
+            assert_eq!(parsed, hash);
+        }
+    }
+
+    #[test_case(" sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73" ; "leading space")]
+    #[test_case("sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73\n" ; "trailing newline")]
+    #[test_case("\tsha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=\r\n" ; "tab and crlf")]
+    fn surrounding_whitespace(input: &str) {
+        assert_eq!(Hash::parse(input), Ok(Hash::compute(HashAlgo::Sha256, b"")));
+    }
+
+    #[test_case("sha256: 0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73" ; "after prefix")]
+    #[test_case("sha256:0mdqa9w1p6cmli6976v4wi0s w9r4p5prkj7lzfd1877wk11c9c73" ; "inside body")]
+    #[test_case("sha256-47DEQpj8HBSa+/TImW+5JCeu\nQeRkm5NMpJWZG3hSuFU=" ; "wrapped line")]
+    fn internal_whitespace(input: &str) {
+        assert_eq!(Hash::parse(input), Err(ParseError::Whitespace));
+    }
+
+    #[test]
+    fn of_hash() {
+        let inner = Hash::compute(HashAlgo::Sha256, b"");
+        let outer = Hash::of_hash(HashAlgo::Sha256, &inner);
+        assert_eq!(
+            outer.to_string(&HashFormat::Base16, false),
+            "0364e78be895cef9249b83b20cdb6362cd3c317f55240669624258bc449a86fc"
+        );
+        let descriptor =
+            "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string();
+        assert_eq!(outer, Hash::compute(HashAlgo::Sha256, descriptor));
+    }
+
+    #[test_case(&[HashAlgo::Md5, HashAlgo::Sha512, HashAlgo::Sha1] => Some(HashAlgo::Sha512))]
+    #[test_case(&[HashAlgo::Sha1, HashAlgo::Md5] => Some(HashAlgo::Sha1))]
+    #[test_case(&[HashAlgo::Blake3, HashAlgo::Sha256, HashAlgo::Md5] => Some(HashAlgo::Sha256))]
+    #[test_case(&[HashAlgo::Sha256, HashAlgo::Blake3] => Some(HashAlgo::Sha256))]
+    #[test_case(&[HashAlgo::Blake3, HashAlgo::Sha1] => Some(HashAlgo::Blake3))]
+    #[cfg_attr(
+        feature = "extra-algos",
+        test_case(&[HashAlgo::Sha224, HashAlgo::Sha1] => Some(HashAlgo::Sha224))
+    )]
+    #[cfg_attr(
+        feature = "extra-algos",
+        test_case(&[HashAlgo::Sha256, HashAlgo::Sha384, HashAlgo::Sha224] => Some(HashAlgo::Sha384))
+    )]
+    #[cfg_attr(
+        feature = "extra-algos",
+        test_case(&[HashAlgo::Sha224, HashAlgo::Blake3] => Some(HashAlgo::Blake3))
+    )]
+    #[cfg_attr(
+        feature = "extra-algos",
+        test_case(&[HashAlgo::Sha512, HashAlgo::Sha384] => Some(HashAlgo::Sha512))
+    )]
+    #[cfg_attr(
+        feature = "extra-algos",
+        test_case(&[HashAlgo::Sha384, HashAlgo::Sha512] => Some(HashAlgo::Sha512))
+    )]
+    #[test_case(&[] => None)]
+    fn strongest(algos: &[HashAlgo]) -> Option<HashAlgo> {
+        HashAlgo::strongest(algos.iter().copied())
+    }
+
+    #[test_case(HashAlgo::Md5 => 0)]
+    #[test_case(HashAlgo::Sha1 => 1)]
+    #[test_case(HashAlgo::Blake3 => 2)]
+    #[test_case(HashAlgo::Sha256 => 2)]
+    #[test_case(HashAlgo::Sha512 => 3)]
+    #[cfg_attr(feature = "extra-algos", test_case(HashAlgo::Sha224 => 2))]
+    #[cfg_attr(feature = "extra-algos", test_case(HashAlgo::Sha384 => 3))]
+    fn strength(algo: HashAlgo) -> u8 {
+        algo.strength()
+    }
+
+    #[test_case(16, &[HashAlgo::Md5])]
+    #[test_case(20, &[HashAlgo::Sha1])]
+    #[test_case(32, &[HashAlgo::Blake3, HashAlgo::Sha256])]
+    #[test_case(64, &[HashAlgo::Sha512])]
+    #[cfg_attr(not(feature = "extra-algos"), test_case(48, &[]))]
+    #[cfg_attr(feature = "extra-algos", test_case(28, &[HashAlgo::Sha224]))]
+    #[cfg_attr(feature = "extra-algos", test_case(48, &[HashAlgo::Sha384]))]
+    fn try_from_size(size: usize, expect: &[HashAlgo]) {
+        assert_eq!(HashAlgo::try_from_size(size), expect);
+    }
+
+    #[test]
+    fn dash_in_unprefixed_hash() {
+        let url_safe = "47DEQpj8HBSa-_TImW-5JCeuQeRkm5NMpJWZG3hSuFU=";
+        assert_eq!(Hash::parse(url_safe), Err(ParseError::MissingPrefix));
+        assert!(matches!(
+            Hash::parse_as(url_safe, HashAlgo::Sha256),
+            Err(ParseError::InvalidEncoding(_))
+        ));
+        let sri = Hash::parse("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=").unwrap();
+        assert_eq!(sri.format(), Some(HashFormat::Sri));
+        assert_eq!(sri, Hash::compute(HashAlgo::Sha256, b""));
+    }
+
+    #[test_case(HashAlgo::Md5, 2)]
+    #[test_case(HashAlgo::Sha1, 0)]
+    #[test_case(HashAlgo::Sha256, 4)]
+    #[test_case(HashAlgo::Sha512, 3)]
+    fn nix32_stray_bits(algo: HashAlgo, stray: usize) {
+        const SYMBOLS: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+        let valid = Hash::compute(algo, b"").to_string(&HashFormat::Nix32, false);
+        assert_eq!(valid.len() * 5 - algo.size() * 8, stray);
+        // the leading digit holds the highest bits, of which `stray` are unused
+        let leading = SYMBOLS
+            .iter()
+            .position(|&c| c == valid.as_bytes()[0])
+            .unwrap();
+        for bit in 5 - stray..5 {
+            let digit = SYMBOLS[leading | 1 << bit] as char;
+            let input = format!("{digit}{}", &valid[1..]);
+            assert_eq!(
+                Hash::parse_as(&input, algo),
+                Err(ParseError::StrayBits { algo })
+            );
+        }
+    }
+
+    #[cfg(feature = "proptest")]
+    proptest::proptest! {
+        #[test]
+        fn parse_roundtrip((hash, string) in super::strategy::hash_string()) {
+            let parsed = Hash::parse_as(&string, hash.algorithm()).unwrap();
+            proptest::prop_assert_eq!(parsed, hash);
+        }
+
+        #[test]
+        fn parse_near_miss((hash, string) in super::strategy::near_miss_string()) {
+            // Dropping base64 padding still decodes, but never to another hash.
+            if let Ok(parsed) = Hash::parse_as(&string, hash.algorithm()) {
+                proptest::prop_assert_eq!(parsed, hash);
+            }
+        }
+
+        #[test]
+        fn parse_csv_record_agrees(
+            (hash, string) in proptest::prop_oneof![
+                super::strategy::hash_string(),
+                super::strategy::near_miss_string(),
+            ]
+        ) {
+            let algo = hash.algorithm();
+            let parsed = Hash::parse_csv_record(&string, &algo.to_string());
+            let expect = Hash::parse_as(&string, algo);
+            proptest::prop_assert_eq!(
+                parsed.as_ref().map(Hash::format),
+                expect.as_ref().map(Hash::format)
+            );
+            proptest::prop_assert_eq!(parsed, expect);
+        }
+    }
+
+    #[test_matrix(
+        [HashAlgo::Md5, HashAlgo::Sha1, HashAlgo::Sha256, HashAlgo::Sha512],
+        [HashFormat::Base64, HashFormat::Nix32, HashFormat::Base16, HashFormat::Sri],
+        [false, true]
+    )]
+    fn parse_csv_record(algo: HashAlgo, format: HashFormat, show_algo: bool) {
+        let string = Hash::compute(algo, b"hello").to_string(&format, show_algo);
+        let parsed = Hash::parse_csv_record(&string, &algo.to_string()).unwrap();
+        let expect = Hash::parse_as(&string, algo).unwrap();
+        assert_eq!(parsed, expect);
+        assert_eq!(parsed.format(), expect.format());
+        assert_eq!(ToString::to_string(&parsed), string);
+    }
+
+    #[test]
+    fn parse_csv_record_error() {
+        let hex = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!(
+            Hash::parse_csv_record(hex, "sha3"),
+            Err(ParseError::UnknownPrefix {
+                found: "sha3".into()
+            })
+        );
+        assert_eq!(
+            Hash::parse_csv_record(hex, "sha1"),
+            Err(ParseError::WrongLength {
+                algo: HashAlgo::Sha1,
+                n_chars: 64
+            })
+        );
+        assert_eq!(
+            Hash::parse_csv_record(&format!("md5:{hex}"), "sha256"),
+            Err(ParseError::ExpectedPrefix {
+                want: HashAlgo::Sha256,
+                found: HashAlgo::Md5
+            })
+        );
+    }
+
+    #[test]
+    fn nix32_error_position() {
+        let mut input = Hash::compute(HashAlgo::Sha256, b"")
+            .to_string(&HashFormat::Nix32, false)
+            .into_bytes();
+        input[3] = b'e';
+        let input = String::from_utf8(input).unwrap();
+        match Hash::parse_as(&input, HashAlgo::Sha256) {
+            Err(ParseError::InvalidEncoding(e)) => assert_eq!(e.position, 3),
+            other => panic!("expected an invalid encoding, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn unsupported_algo() {
+        #[cfg(not(feature = "extra-algos"))]
+        assert_eq!(
+            Hash::parse("sha384-OLBgp1GsljhM2TJ+sbHjaiH9txEUvgdDTAzHv2P24donTt6/529l+9Ua0vFImLlb"),
+            Err(ParseError::UnsupportedAlgo {
+                found: "sha384".into()
+            })
+        );
+        #[cfg(not(feature = "extra-algos"))]
+        assert_eq!(
+            Hash::parse("SHA224:d14a028c2a3a2bc9476102bb288234c415a2b01f828ea62ac5b3e42f"),
+            Err(ParseError::UnsupportedAlgo {
+                found: "SHA224".into()
+            })
+        );
+        assert_eq!(
+            Hash::parse("sha3:aaaa"),
+            Err(ParseError::UnknownPrefix {
+                found: "sha3".into()
+            })
+        );
+    }
+
+    #[cfg(feature = "extra-algos")]
+    #[test_matrix(
+        [
+            "SHA224:d14a028c2a3a2bc9476102bb288234c415a2b01f828ea62ac5b3e42f",
+            "sha384-OLBgp1GsljhM2TJ+sbHjaiH9txEUvgdDTAzHv2P24donTt6/529l+9Ua0vFImLlb"
+        ],
+        [HashFormat::Base64, HashFormat::Nix32, HashFormat::Base16, HashFormat::Sri]
+    )]
+    fn extra_algos_round_trip(input: &str, format: HashFormat) {
+        let hash = Hash::parse(input).unwrap();
+        assert_eq!(hash, Hash::compute(hash.algorithm(), b""));
+        for show_algo in [false, true] {
+            let string = hash.to_string(&format, show_algo);
+            assert_eq!(Hash::parse_as(&string, hash.algorithm()), Ok(hash.clone()));
+        }
+    }
+
+    #[test]
+    fn prefix_case() {
+        let empty = Hash::compute(HashAlgo::Sha256, b"");
+        let upper = Hash::parse("SHA256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=").unwrap();
+        assert_eq!(upper, empty);
+        assert_eq!(upper.format(), Some(HashFormat::Sri));
+        let mixed =
+            Hash::parse("Sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+                .unwrap();
+        assert_eq!(mixed, empty);
+    }
+
+    #[test_case(
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855", HashFormat::Base16
+        ; "base16"
+    )]
+    #[test_case("0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73", HashFormat::Nix32 ; "nix32")]
+    #[test_case("47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=", HashFormat::Base64 ; "base64")]
+    #[test_case("47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU", HashFormat::Base64 ; "base64 unpadded")]
+    #[test_case("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=", HashFormat::Sri ; "sri")]
+    #[test_case(
+        "sha256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=", HashFormat::Base64
+        ; "base64 with prefix"
+    )]
+    fn parse_with_format(input: &str, format: HashFormat) {
+        let hash = Hash::parse_with_format(input, HashAlgo::Sha256, format).unwrap();
+        assert_eq!(hash, Hash::compute(HashAlgo::Sha256, b""));
+        assert_eq!(hash.format(), Some(format));
+    }
+
+    #[test_case(
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b85", HashFormat::Base16
+        => ParseError::WrongLength { algo: HashAlgo::Sha256, n_chars: 63 }
+        ; "base16 too short"
+    )]
+    #[test_case(
+        "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c7", HashFormat::Nix32
+        => ParseError::WrongLength { algo: HashAlgo::Sha256, n_chars: 51 }
+        ; "nix32 too short"
+    )]
+    #[test_case(
+        "2jmj7l5rSw0yVb/vlWAYkK/YBwk=", HashFormat::Base64
+        => ParseError::InvalidHash { algo: HashAlgo::Sha256, n_bytes: 20 }
+        ; "base64 of a sha1"
+    )]
+    #[test_case(
+        "sha256-2jmj7l5rSw0yVb/vlWAYkK/YBwk=", HashFormat::Sri
+        => ParseError::InvalidHash { algo: HashAlgo::Sha256, n_bytes: 20 }
+        ; "sri of a sha1"
+    )]
+    #[test_case(
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855", HashFormat::Nix32
+        => ParseError::WrongLength { algo: HashAlgo::Sha256, n_chars: 64 }
+        ; "base16 as nix32"
+    )]
+    #[test_case(
+        "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=", HashFormat::Base64
+        => ParseError::ExpectedFormat { want: HashFormat::Base64 }
+        ; "sri as base64"
+    )]
+    #[test_case(
+        "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=", HashFormat::Sri
+        => ParseError::ExpectedFormat { want: HashFormat::Sri }
+        ; "base64 as sri"
+    )]
+    #[test_case(
+        "sha1-2jmj7l5rSw0yVb/vlWAYkK/YBwk=", HashFormat::Sri
+        => ParseError::ExpectedPrefix { want: HashAlgo::Sha256, found: HashAlgo::Sha1 }
+        ; "other algorithm"
+    )]
+    fn parse_with_format_error(input: &str, format: HashFormat) -> ParseError {
+        Hash::parse_with_format(input, HashAlgo::Sha256, format).unwrap_err()
+    }
+
+    #[test_case("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=", HashAlgo::Sha256 ; "sha256")]
+    #[test_case("sha1-2jmj7l5rSw0yVb/vlWAYkK/YBwk=", HashAlgo::Sha1 ; "sha1")]
+    fn parse_sri_strict(input: &str, algo: HashAlgo) {
+        let hash = Hash::parse_sri_strict(input).unwrap();
+        assert_eq!(hash, Hash::compute(algo, b""));
+        assert_eq!(hash.format(), Some(HashFormat::Sri));
+        assert_eq!(ToString::to_string(&hash), input);
+    }
+
+    #[test_case(
+        "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        => ParseError::NotSri
+        ; "prefixed hex"
+    )]
+    #[test_case(
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855" => ParseError::NotSri
+        ; "bare hex"
+    )]
+    #[test_case(
+        "sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73" => ParseError::NotSri
+        ; "prefixed nix32"
+    )]
+    #[test_case("0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73" => ParseError::NotSri ; "bare nix32")]
+    #[test_case(
+        "sha256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=" => ParseError::NotSri
+        ; "prefixed base64"
+    )]
+    #[test_case(
+        "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU"
+        => ParseError::WrongLength { algo: HashAlgo::Sha256, n_chars: 43 }
+        ; "unpadded"
+    )]
+    #[cfg_attr(not(feature = "extra-algos"), test_case(
+        "sha384-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+        => ParseError::UnsupportedAlgo { found: "sha384".into() }
+        ; "unsupported algorithm"
+    ))]
+    fn parse_sri_strict_error(input: &str) -> ParseError {
+        Hash::parse_sri_strict(input).unwrap_err()
+    }
+
+    #[test_case("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=" ; "sri")]
+    #[test_case("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855" ; "hex")]
+    #[test_case("0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73" ; "nix32")]
+    fn display_alternate(input: &str) {
+        let hash = Hash::parse_as(input, HashAlgo::Sha256).unwrap();
+        assert_eq!(
+            format!("{hash:#}"),
+            "sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"
+        );
+        assert_eq!(format!("{hash}"), input);
+    }
+
+    #[test]
+    fn sort() {
+        let empty = |algo| Hash::compute(algo, b"");
+        let hello = |algo| Hash::compute(algo, b"hello");
+        let sha256 = |input: &str| Hash::parse_as(input, HashAlgo::Sha256).unwrap();
+        let mut hashes = vec![
+            hello(HashAlgo::Sha1),
+            sha256("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="),
+            hello(HashAlgo::Md5),
+            empty(HashAlgo::Sha512),
+            sha256("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+            empty(HashAlgo::Blake3),
+            sha256("0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"),
+            hello(HashAlgo::Sha256),
+            empty(HashAlgo::Sha1),
+        ];
+        let mut sorted = hashes.clone();
+        sorted.sort_by(|a, b| a.csv_sort_key().cmp(&b.csv_sort_key()));
+        let mut reversed = hashes.clone();
+        reversed.reverse();
+        reversed.sort_by(|a, b| a.csv_sort_key().cmp(&b.csv_sort_key()));
+        let strings = |hashes: &[Hash]| hashes.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(strings(&sorted), strings(&reversed));
+        assert_eq!(
+            strings(&sorted[5..8]),
+            [
+                "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73",
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+                "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+            ]
+        );
+
+        hashes.sort();
+        assert!(hashes.is_sorted_by_key(|hash| (hash.algorithm(), hash.bytes().to_vec())));
+        let algos = hashes.iter().map(Hash::algorithm).collect::<Vec<_>>();
+        assert_eq!(
+            algos,
+            [
+                HashAlgo::Blake3,
+                HashAlgo::Md5,
+                HashAlgo::Sha1,
+                HashAlgo::Sha1,
+                HashAlgo::Sha256,
+                HashAlgo::Sha256,
+                HashAlgo::Sha256,
+                HashAlgo::Sha256,
+                HashAlgo::Sha512,
+            ]
+        );
+        assert_eq!(sorted, hashes);
+    }
+
+    #[test_case("ni:///sha-256;", "", HashAlgo::Sha256 ; "no authority")]
+    #[test_case("ni://example.com/sha-256;", "?ct=text/plain", HashAlgo::Sha256 ; "authority and query")]
+    #[test_case("NI:///sha-256;", "", HashAlgo::Sha256 ; "uppercase scheme")]
+    #[test_case("ni:///sha-512;", "", HashAlgo::Sha512 ; "sha512")]
+    #[cfg_attr(
+        feature = "extra-algos",
+        test_case("ni:///sha-384;", "", HashAlgo::Sha384 ; "sha384")
+    )]
+    fn parse_ni(prefix: &str, suffix: &str, algo: HashAlgo) {
+        let expect = Hash::compute(algo, "Hello World!");
+        let uri = format!("{prefix}{}{suffix}", BASE64URL_NOPAD.encode(expect.bytes()));
+        let hash = Hash::parse_ni(&uri).unwrap();
+        assert_eq!(hash, expect);
+        assert_eq!(hash.format(), None);
+    }
+
+    #[test_case(
+        "ni:///sha-256-128;UyaQV-Ev4rdLoHyJJWCi11OHfrY"
+        => ParseError::UnsupportedAlgo { found: "sha-256-128".into() }
+        ; "truncated"
+    )]
+    #[test_case(
+        "ni:///sha256;UyaQV-Ev4rdLoHyJJWCi11OHfrYv9E1aGQAlMO2X_-Q"
+        => ParseError::UnsupportedAlgo { found: "sha256".into() }
+        ; "nix algorithm name"
+    )]
+    #[test_case(
+        "ni:///sha-512;UyaQV-Ev4rdLoHyJJWCi11OHfrYv9E1aGQAlMO2X_-Q"
+        => ParseError::InvalidHash { algo: HashAlgo::Sha512, n_bytes: 32 }
+        ; "wrong length"
+    )]
+    #[test_case(
+        "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=" => ParseError::NotNi
+        ; "sri"
+    )]
+    #[test_case("ni:sha-256;UyaQV-Ev4rdLoHyJJWCi11OHfrYv9E1aGQAlMO2X_-Q" => ParseError::NotNi ; "no slashes")]
+    #[test_case("ni:///sha-256" => ParseError::NotNi ; "no digest")]
+    fn parse_ni_error(uri: &str) -> ParseError {
+        Hash::parse_ni(uri).unwrap_err()
+    }
+
+    #[test]
+    fn parse_ni_padded() {
+        let uri = "ni:///sha-256;47DEQpj8HBSa-_TImW-5JCeuQeRkm5NMpJWZG3hSuFU=";
+        assert!(matches!(
+            Hash::parse_ni(uri),
+            Err(ParseError::InvalidEncoding(_))
+        ));
+    }
+
+    // `max_size` and every fixed buffer are sized from `size`, so it has to
+    // agree with the digests, and `all` has to list every algorithm.
+    #[test]
+    fn algo_sizes() {
+        use strum::EnumCount;
+
+        assert_eq!(HashAlgo::all().len(), HashAlgo::COUNT);
+        for &algo in HashAlgo::all() {
+            assert_eq!(
+                Hash::compute(algo, b"").bytes().len(),
+                algo.size(),
+                "{algo}"
+            );
+            assert_eq!(algo.digest_new().output_size(), algo.size(), "{algo}");
+            assert_eq!(Hasher::new(algo).algorithm(), algo);
+            assert!(algo.size() <= HashAlgo::max_size());
+            assert!(HashAlgo::max_size() <= MAX_HASH_SIZE);
+        }
+    }
+
+    #[test]
+    fn key() {
+        use std::collections::HashSet;
+
+        let hex =
+            Hash::parse("sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        let sri = Hash::parse("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=");
+        let (hex, sri) = (hex.unwrap(), sri.unwrap());
+        assert_eq!(hex.key(), sri.key());
+        assert_eq!(hex.key().bytes(), hex.bytes());
+        assert_eq!(hex.key().algorithm(), HashAlgo::Sha256);
+
+        let keys = HashAlgo::all()
+            .iter()
+            .flat_map(|&algo| [Hash::compute(algo, ""), Hash::compute(algo, "hello")])
+            .chain([hex, sri])
+            .map(|hash| HashKey::from(&hash))
+            .collect::<HashSet<_>>();
+        assert_eq!(keys.len(), HashAlgo::all().len() * 2);
+    }
+
+    #[test]
+    fn ct_eq() {
+        let empty = Hash::compute(HashAlgo::Sha256, b"");
+        let hello = Hash::compute(HashAlgo::Sha256, b"hello");
+        let empty_sha512 = Hash::compute(HashAlgo::Sha512, b"");
+        let empty_sri = Hash::parse("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=").unwrap();
+        for (a, b) in [
+            (&empty, &empty),
+            (&empty, &empty_sri),
+            (&empty, &hello),
+            (&empty, &empty_sha512),
+            (&empty_sha512, &empty),
+        ] {
+            assert_eq!(a.ct_eq(b), a == b, "{a} and {b}");
+        }
+        assert!(empty.ct_eq(&empty_sri));
+        assert!(!empty.ct_eq(&hello));
+    }
+
+    #[test]
+    fn matches_input() {
+        let known = Hash::compute(HashAlgo::Sha256, b"");
+        let hex = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert!(known.matches_input(hex));
+        assert!(known.matches_input(&format!("sha256:{hex}")));
+        assert!(known.matches_input("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="));
+        assert!(known.matches_input("0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"));
+        assert!(!known.matches_input(&hex.replace('e', "f")));
+        assert!(!known.matches_input("sha1:da39a3ee5e6b4b0d3255bfef95601890afd80709"));
+        assert!(!known.matches_input("not a hash"));
+    }
+
+    #[test]
+    fn into_string() {
+        let sri = "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+        let hash = Hash::compute(HashAlgo::Sha256, b"");
+        assert_eq!(String::from(hash.clone()), sri);
+        let base16 = hash.to_string(&HashFormat::Base16, true);
+        assert_eq!(hash.into_string(&HashFormat::Base16, true), base16);
+
+        let parsed = Hash::parse(&base16).unwrap();
+        assert_eq!(String::from(parsed), base16);
+    }
+
+    #[test]
+    fn try_into_array() {
+        let hash = hash_string("abc", HashAlgo::Sha256);
+        let array: [u8; 32] = hash.try_into_array().unwrap();
+        assert_eq!(array, sha2::Sha256::digest("abc").as_slice());
+        assert_eq!(
+            hash.try_into_array::<20>(),
+            Err(SizeMismatch {
+                want: 20,
+                found: 32
+            })
+        );
+        assert_eq!(
+            hash.try_into_array::<64>(),
+            Err(SizeMismatch {
+                want: 64,
+                found: 32
+            })
+        );
+    }
+
+    #[cfg(feature = "smol")]
+    #[test]
+    fn compute_file() {
+        use std::io::Write;
+
+        let data = "Rust is okay, but C++ is a blight.\n".repeat(10_000);
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(data.as_bytes()).unwrap();
+        for &algo in HashAlgo::all() {
+            let hash = smol::block_on(Hash::compute_file(algo, file.path())).unwrap();
+            assert_eq!(hash, Hash::compute(algo, data.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn serde_roundtrip() {
+        static LOCKED: &str =
+            r#"{"narHash":"sha256-JO+lFN2HsCwSLMUWXHeOad6QUxOuwe9UOAF/iSl1J4I="}"#;
+
+        #[derive(serde::Serialize, serde::Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Locked {
+            nar_hash: Hash,
+        }
+
+        let locked = serde_json::from_str::<Locked>(LOCKED).unwrap();
+        assert_eq!(locked.nar_hash.algorithm(), HashAlgo::Sha256);
+        assert_eq!(serde_json::to_string(&locked).unwrap(), LOCKED);
+    }
+
+    #[test_case("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855" ; "hex")]
+    #[test_case("0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73" ; "nix32")]
+    #[test_case("47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=" ; "base64")]
+    fn serde_roundtrip_bare(input: &str) {
+        let hash = Hash::parse_as(input, HashAlgo::Sha256).unwrap();
+        let json = serde_json::to_string(&hash).unwrap();
+        assert_eq!(json, format!("\"sha256:{input}\""));
+        let parsed = serde_json::from_str::<Hash>(&json).unwrap();
+        assert_eq!(parsed, hash);
+        assert_eq!(parsed.format(), hash.format());
+    }
+
+    #[test]
+    fn serde_with_format() {
+        #[derive(serde::Serialize, serde::Deserialize)]
+        struct Pinned {
+            #[serde(with = "super::format::sri")]
+            sri: Hash,
+            #[serde(with = "super::format::nix32")]
+            nix32: Hash,
+        }
+
+        let hash = hash_string("abc", HashAlgo::Sha256);
+        let base16 = hash.to_string(&HashFormat::Base16, true);
+        let pinned = serde_json::from_value::<Pinned>(serde_json::json!({
+            "sri": base16,
+            "nix32": base16,
+        }))
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(&pinned).unwrap(),
+            serde_json::json!({
+                "sri": hash.to_string(&HashFormat::Sri, true),
+                "nix32": hash.to_string(&HashFormat::Nix32, true),
+            })
+        );
+    }
+
+    #[test]
+    fn from_digest() {
+        let mut digest = sha2::Sha256::new();
+        digest.update("abc");
+        let hash = Hash::from_digest(HashAlgo::Sha256, digest).unwrap();
+        assert_eq!(hash, hash_string("abc", HashAlgo::Sha256));
+        assert_eq!(
+            Hash::from_digest(HashAlgo::Sha256, sha2::Sha512::new()),
+            Err(ParseError::InvalidHash {
+                algo: HashAlgo::Sha256,
+                n_bytes: 64
+            })
+        );
+    }
+
     #[test_matrix(
         [HashAlgo::Blake3, HashAlgo::Md5, HashAlgo::Sha1, HashAlgo::Sha256, HashAlgo::Sha512],
         [HashFormat::Base16, HashFormat::Nix32, HashFormat::Base64, HashFormat::Sri],
@@ -318,6 +1860,37 @@ mod tests {
         assert_eq!(hash, decoded);
     }
 
+    #[test_matrix(
+        [HashAlgo::Blake3, HashAlgo::Md5, HashAlgo::Sha1, HashAlgo::Sha256, HashAlgo::Sha512],
+        [HashFormat::Base64, HashFormat::Sri]
+    )]
+    fn unpadded_base64(algo: HashAlgo, format: HashFormat) {
+        static S: &str = "Rust is okay, but C++ is a blight.";
+        let hash = hash_string(S, algo);
+        let encoded = hash.to_string(&format, true);
+        let unpadded = encoded.trim_end_matches('=');
+        assert_ne!(encoded.len(), unpadded.len());
+        let decoded = Hash::parse(unpadded).unwrap();
+        assert_eq!(decoded.format(), Some(format));
+        assert_eq!(hash, decoded);
+    }
+
+    #[test_case(
+        "sha256:ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0"
+        => "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        ; "SHA256 unpadded (43 chars)"
+    )]
+    #[test_case(
+        "sha512:3a81oZNherrMQXNJriBBMRLm+k6JqX6iCp7u5ktV05ohkpkqJ0/BqDa6PCOj/uu9RU1EI2Q86A4qmslPpUyknw"
+        => "sha512:ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+        ; "SHA512 unpadded (86 chars)"
+    )]
+    fn unpadded_base64_known(input: &str) -> String {
+        Hash::parse(input)
+            .unwrap()
+            .to_string(&HashFormat::Base16, true)
+    }
+
     // MD5 (16 bytes): non-SRI cannot be too short by length-inference; but it
     // CAN be too long (18). SRI can be too short (15) or too long (18).
     #[test_case(
@@ -419,4 +1992,16 @@ mod tests {
     fn invalid_hash(input: &str) -> ParseError {
         Hash::parse(input).unwrap_err()
     }
+
+    // 43 characters is unpadded base64 of 32 bytes. A length of 1 mod 4 is
+    // never base64, so there is no decoded byte count to report for 45.
+    #[test_case("47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU" => Ok(HashFormat::Base64) ; "43 chars")]
+    #[test_case(
+        "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=A"
+        => Err(ParseError::WrongLength { algo: HashAlgo::Sha256, n_chars: 45 })
+        ; "45 chars"
+    )]
+    fn hinted_sha256_base64(input: &str) -> Result<HashFormat, ParseError> {
+        Hash::detect_format(input, HashAlgo::Sha256)
+    }
 }
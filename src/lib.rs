@@ -1,3 +1,13 @@
+pub mod derivation;
 pub mod fetchers;
 pub mod flake;
 pub mod hash;
+// Archives need Unix file modes, and symlink targets as raw bytes.
+#[cfg(unix)]
+pub mod nar;
+pub mod npins;
+pub mod prefetch;
+pub mod process;
+pub mod store_path;
+#[cfg(feature = "testutil")]
+pub mod testutil;
@@ -0,0 +1,151 @@
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::hash::{Hash, HashAlgo, Hasher};
+
+const NAR_VERSION_MAGIC: &[u8] = b"nix-archive-1";
+
+// <https://github.com/NixOS/nix/blob/c9211b0b2d52a26ed666780b763b39a5bddd3fb3/src/libutil/archive.cc>
+pub fn dump_path(path: impl AsRef<Path>, mut sink: impl Write) -> io::Result<()> {
+    write_str(&mut sink, NAR_VERSION_MAGIC)?;
+    dump_node(path.as_ref(), &mut sink)
+}
+
+pub fn nar_hash(path: impl AsRef<Path>) -> io::Result<Hash> {
+    let mut hasher = Hasher::new(HashAlgo::Sha256);
+    dump_path(path, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+fn dump_node<W: Write>(path: &Path, sink: &mut W) -> io::Result<()> {
+    let meta = std::fs::symlink_metadata(path)?;
+    write_str(sink, b"(")?;
+    if meta.is_symlink() {
+        let target = std::fs::read_link(path)?;
+        write_str(sink, b"type")?;
+        write_str(sink, b"symlink")?;
+        write_str(sink, b"target")?;
+        write_str(sink, target.as_os_str().as_bytes())?;
+    } else if meta.is_file() {
+        write_str(sink, b"type")?;
+        write_str(sink, b"regular")?;
+        if meta.permissions().mode() & 0o100 != 0 {
+            write_str(sink, b"executable")?;
+            write_str(sink, b"")?;
+        }
+        write_str(sink, b"contents")?;
+        let file = std::fs::File::open(path)?;
+        let len = meta.len();
+        sink.write_all(&len.to_le_bytes())?;
+        let copied = io::copy(&mut file.take(len), sink)?;
+        if copied != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "file `{}` changed while it was being archived",
+                    path.display()
+                ),
+            ));
+        }
+        write_padding(sink, len)?;
+    } else if meta.is_dir() {
+        write_str(sink, b"type")?;
+        write_str(sink, b"directory")?;
+        let mut names = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        for name in names {
+            write_str(sink, b"entry")?;
+            write_str(sink, b"(")?;
+            write_str(sink, b"name")?;
+            write_str(sink, name.as_bytes())?;
+            write_str(sink, b"node")?;
+            dump_node(&path.join(&name), sink)?;
+            write_str(sink, b")")?;
+        }
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("file `{}` has an unsupported type", path.display()),
+        ));
+    }
+    write_str(sink, b")")
+}
+
+fn write_str(sink: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    sink.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    sink.write_all(bytes)?;
+    write_padding(sink, bytes.len() as u64)
+}
+
+fn write_padding(sink: &mut impl Write, len: u64) -> io::Result<()> {
+    let padding = (8 - len % 8) % 8;
+    sink.write_all(&[0; 8][..padding as usize])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    use test_case::test_case;
+
+    use super::{dump_path, nar_hash};
+    use crate::hash::HashFormat;
+
+    fn fixture() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        std::fs::write(path.join("hello.txt"), "Hello, world!\n").unwrap();
+        std::fs::create_dir(path.join("bin")).unwrap();
+        std::fs::write(path.join("bin/run"), "#!/bin/sh\necho hi\n").unwrap();
+        let permissions = std::fs::Permissions::from_mode(0o755);
+        std::fs::set_permissions(path.join("bin/run"), permissions).unwrap();
+        std::os::unix::fs::symlink("hello.txt", path.join("link")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn dump_regular() {
+        let dir = fixture();
+        let mut nar = Vec::new();
+        dump_path(dir.path().join("hello.txt"), &mut nar).unwrap();
+        let mut expect = Vec::new();
+        for s in [
+            &b"nix-archive-1"[..],
+            b"(",
+            b"type",
+            b"regular",
+            b"contents",
+            b"Hello, world!\n",
+            b")",
+        ] {
+            expect.extend((s.len() as u64).to_le_bytes());
+            expect.extend(s);
+            expect.resize(expect.len().next_multiple_of(8), 0);
+        }
+        assert_eq!(nar, expect);
+    }
+
+    // Expected hashes were computed with an independent implementation of the format.
+    #[test_case(
+        Path::new("") => "sha256-9gB7mv9hmw2Ty1jojqtPEVTI0+rZenA80N5i7v4HjDA="
+        ; "directory"
+    )]
+    #[test_case(
+        Path::new("hello.txt") => "sha256-WEk3mouMv8QTHQwPia0CDqJw2F8WHiV7fVITW1gzfs4="
+        ; "regular"
+    )]
+    #[test_case(
+        Path::new("link") => "sha256-AfioPXiFvhTtxo+kM26BpXp1QmwgoPyfm8osj+r3Y4c="
+        ; "symlink"
+    )]
+    fn hash(path: &Path) -> String {
+        let dir = fixture();
+        let hash = nar_hash(dir.path().join(path)).unwrap();
+        hash.to_string(&HashFormat::Sri, false)
+    }
+}
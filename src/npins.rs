@@ -0,0 +1,347 @@
+use std::str::FromStr;
+
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::fetchers::git::GitInputScheme;
+use crate::hash::{Hash, HashAlgo, HashFormat, ParseError};
+
+pub const SUPPORTED_NPINS_VERSION: u32 = 5;
+
+// `sources.json` as read by the `default.nix` that npins generates.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NpinsSources {
+    pub pins: IndexMap<String, Pin>,
+    pub version: u32,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum NpinsError {
+    #[error("sources version {0} is not supported, expected {SUPPORTED_NPINS_VERSION}")]
+    UnsupportedVersion(u32),
+    #[error("sources are malformed: {0}")]
+    Malformed(#[from] serde_json::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PinConversionError {
+    #[error("`{0}` pins are not git repositories")]
+    NotGit(&'static str),
+    #[error("pin has an invalid repository url: {0}")]
+    InvalidUrl(#[from] url::ParseError),
+    #[error("pin has an invalid hash: {0}")]
+    InvalidHash(#[from] ParseError),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Pin {
+    Git(GitPin),
+    GitRelease(GitReleasePin),
+    PyPi(PyPiPin),
+    Channel(ChannelPin),
+    Tarball(TarballPin),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Repository {
+    Git {
+        url: String,
+    },
+    GitHub {
+        owner: String,
+        repo: String,
+    },
+    GitLab {
+        repo_path: String,
+        server: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        private_token: Option<String>,
+    },
+    Forgejo {
+        server: String,
+        owner: String,
+        repo: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GitPin {
+    pub repository: Repository,
+    pub branch: String,
+    #[serde(default)]
+    pub submodules: bool,
+    pub revision: String,
+    pub url: Option<String>,
+    pub hash: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GitReleasePin {
+    pub repository: Repository,
+    #[serde(default)]
+    pub pre_releases: bool,
+    pub version_upper_bound: Option<String>,
+    pub release_prefix: Option<String>,
+    #[serde(default)]
+    pub submodules: bool,
+    pub version: String,
+    pub revision: String,
+    pub url: Option<String>,
+    pub hash: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PyPiPin {
+    pub name: String,
+    pub version_upper_bound: Option<String>,
+    pub version: String,
+    pub url: String,
+    pub hash: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChannelPin {
+    pub name: String,
+    pub url: String,
+    pub hash: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TarballPin {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locked_url: Option<String>,
+    pub hash: String,
+}
+
+impl FromStr for NpinsSources {
+    type Err = NpinsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let sources = serde_json::from_str::<Self>(s)?;
+        if sources.version != SUPPORTED_NPINS_VERSION {
+            return Err(NpinsError::UnsupportedVersion(sources.version));
+        }
+        Ok(sources)
+    }
+}
+
+impl Pin {
+    pub fn hash(&self) -> &str {
+        match self {
+            Pin::Git(pin) => &pin.hash,
+            Pin::GitRelease(pin) => &pin.hash,
+            Pin::PyPi(pin) => &pin.hash,
+            Pin::Channel(pin) => &pin.hash,
+            Pin::Tarball(pin) => &pin.hash,
+        }
+    }
+
+    // A pin fetched with git hashes the same tree as the git fetcher, so its
+    // hash carries over as the `narHash`. Pins with an archive `url` are
+    // hashed over the forge's tarball, which has `export-ignore` and
+    // `export-subst` applied, so they are left without one.
+    pub fn to_git_scheme(&self) -> Result<GitInputScheme, PinConversionError> {
+        let (repository, r#ref, submodules, revision, url, hash) = match self {
+            Pin::Git(pin) => (
+                &pin.repository,
+                Some(pin.branch.clone()),
+                pin.submodules,
+                &pin.revision,
+                &pin.url,
+                &pin.hash,
+            ),
+            Pin::GitRelease(pin) => (
+                &pin.repository,
+                None,
+                pin.submodules,
+                &pin.revision,
+                &pin.url,
+                &pin.hash,
+            ),
+            Pin::PyPi(_) => return Err(PinConversionError::NotGit("PyPi")),
+            Pin::Channel(_) => return Err(PinConversionError::NotGit("Channel")),
+            Pin::Tarball(_) => return Err(PinConversionError::NotGit("Tarball")),
+        };
+        let nar_hash = match url {
+            Some(_) => None,
+            None => {
+                let hash = Hash::parse_as(hash, HashAlgo::Sha256)?;
+                Some(hash.to_string(&HashFormat::Sri, true))
+            }
+        };
+        Ok(GitInputScheme {
+            r#ref,
+            rev: Some(revision.clone()),
+            submodules,
+            nar_hash,
+            ..GitInputScheme::new(repository.git_url()?)
+        })
+    }
+}
+
+impl Repository {
+    pub fn git_url(&self) -> Result<Url, url::ParseError> {
+        match self {
+            Repository::Git { url } => Url::parse(url),
+            Repository::GitHub { owner, repo } => {
+                Url::parse(&format!("https://github.com/{owner}/{repo}.git"))
+            }
+            Repository::GitLab {
+                repo_path, server, ..
+            } => Url::parse(server)?.join(&format!("{repo_path}.git")),
+            Repository::Forgejo {
+                server,
+                owner,
+                repo,
+            } => Url::parse(server)?.join(&format!("{owner}/{repo}.git")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{NpinsError, NpinsSources, Pin, PinConversionError, Repository};
+    use crate::hash::Hash;
+
+    static BUNDLED: &str = include_str!("../data/src/nixpkgs-hashes/npins/sources.json");
+
+    static SOURCES: &str = r#"{
+  "pins": {
+    "home-manager": {
+      "type": "Git",
+      "repository": {
+        "type": "GitHub",
+        "owner": "nix-community",
+        "repo": "home-manager"
+      },
+      "branch": "master",
+      "submodules": false,
+      "revision": "0f4e5b4999fd6a42ece5da8a3a2439a50e48e486",
+      "url": "https://github.com/nix-community/home-manager/archive/0f4e5b4999fd6a42ece5da8a3a2439a50e48e486.tar.gz",
+      "hash": "0n1ik7sfc2fqbw9hgypm7c8zvs6pf3cr8fxjhlcmra8sn1zbqdz1"
+    },
+    "dotfiles": {
+      "type": "Git",
+      "repository": {
+        "type": "Git",
+        "url": "https://git.example.com/dotfiles.git"
+      },
+      "branch": "main",
+      "submodules": true,
+      "revision": "9a4c1e8f3b2d7a6e5c4b3a291807f6e5d4c3b2a1",
+      "url": null,
+      "hash": "1dj5aacvl9qphkd9m797z2awbwscb38vlf6j2vcgyq5121sjqkra"
+    }
+  },
+  "version": 5
+}"#;
+
+    #[test]
+    fn bundled_channel() {
+        let sources = BUNDLED.parse::<NpinsSources>().unwrap();
+        let Pin::Channel(nixpkgs) = &sources.pins["nixpkgs"] else {
+            panic!("`nixpkgs` is not a channel pin");
+        };
+        assert_eq!(nixpkgs.name, "nixos-unstable");
+        assert_eq!(
+            sources.pins["nixpkgs"].hash(),
+            "1dj5aacvl9qphkd9m797z2awbwscb38vlf6j2vcgyq5121sjqkra"
+        );
+    }
+
+    #[test]
+    fn git_pins() {
+        let sources = SOURCES.parse::<NpinsSources>().unwrap();
+        let names = sources.pins.keys().collect::<Vec<_>>();
+        assert_eq!(names, ["home-manager", "dotfiles"]);
+
+        let Pin::Git(github) = &sources.pins["home-manager"] else {
+            panic!("`home-manager` is not a git pin");
+        };
+        assert_eq!(
+            github.repository,
+            Repository::GitHub {
+                owner: "nix-community".into(),
+                repo: "home-manager".into()
+            }
+        );
+        assert!(github.url.is_some());
+
+        let Pin::Git(git) = &sources.pins["dotfiles"] else {
+            panic!("`dotfiles` is not a git pin");
+        };
+        assert!(matches!(&git.repository, Repository::Git { url } if url.ends_with(".git")));
+        assert!(git.submodules);
+        assert_eq!(git.url, None);
+
+        let value = serde_json::to_value(&sources).unwrap();
+        assert_eq!(
+            value,
+            serde_json::from_str::<serde_json::Value>(SOURCES).unwrap()
+        );
+    }
+
+    #[test]
+    fn to_git_scheme() {
+        let sources = SOURCES.parse::<NpinsSources>().unwrap();
+
+        let github = sources.pins["home-manager"].to_git_scheme().unwrap();
+        assert_eq!(
+            github.url.as_str(),
+            "https://github.com/nix-community/home-manager.git"
+        );
+        assert_eq!(github.r#ref.as_deref(), Some("master"));
+        assert_eq!(
+            github.rev.as_deref(),
+            Some("0f4e5b4999fd6a42ece5da8a3a2439a50e48e486")
+        );
+        assert!(!github.submodules);
+        // Fetched as an archive, so its hash is not a `narHash`.
+        assert_eq!(github.nar_hash, None);
+        assert!(!github.is_locked());
+
+        let git = sources.pins["dotfiles"].to_git_scheme().unwrap();
+        assert_eq!(git.url.as_str(), "https://git.example.com/dotfiles.git");
+        assert_eq!(git.r#ref.as_deref(), Some("main"));
+        assert!(git.submodules);
+        assert!(git.is_locked());
+        let nar_hash = git.nar_hash.unwrap();
+        assert!(nar_hash.starts_with("sha256-"));
+        let nar_hash = Hash::parse(&nar_hash).unwrap();
+        assert!(nar_hash.matches_input(sources.pins["dotfiles"].hash()));
+
+        let bundled = BUNDLED.parse::<NpinsSources>().unwrap();
+        assert!(matches!(
+            bundled.pins["nixpkgs"].to_git_scheme(),
+            Err(PinConversionError::NotGit("Channel"))
+        ));
+    }
+
+    #[test]
+    fn gitlab_url() {
+        let repository = Repository::GitLab {
+            repo_path: "group/project".into(),
+            server: "https://gitlab.example.com/".into(),
+            private_token: None,
+        };
+        assert_eq!(
+            repository.git_url().unwrap().as_str(),
+            "https://gitlab.example.com/group/project.git"
+        );
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let sources = json!({ "pins": {}, "version": 3 }).to_string();
+        assert!(matches!(
+            sources.parse::<NpinsSources>(),
+            Err(NpinsError::UnsupportedVersion(3))
+        ));
+    }
+}
@@ -0,0 +1,86 @@
+use std::io;
+use std::process::{Command, Stdio};
+
+use serde::Deserialize;
+use url::Url;
+
+use crate::hash::{Hash, HashAlgo};
+use crate::process::check_status;
+
+// The part of `nix store prefetch-file --json` that is needed here.
+// <https://github.com/NixOS/nix/blob/c9211b0b2d52a26ed666780b763b39a5bddd3fb3/src/nix/prefetch.cc>
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PrefetchedFile {
+    hash: String,
+}
+
+// Downloads `url` into the store and returns the flat hash of its contents, as
+// `nix-prefetch-url` would print it.
+pub fn prefetch_url(url: &Url, algo: HashAlgo) -> io::Result<Hash> {
+    let output = Command::new("nix")
+        .args(["--extra-experimental-features", "nix-command"])
+        .args(["store", "prefetch-file", "--json", "--hash-type"])
+        .arg(algo.to_string())
+        .arg(url.as_str())
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()?;
+    check_status(output.status)?;
+    parse_prefetched_file(&output.stdout, algo)
+}
+
+fn parse_prefetched_file(json: &[u8], algo: HashAlgo) -> io::Result<Hash> {
+    let file = serde_json::from_slice::<PrefetchedFile>(json)?;
+    let hash = Hash::parse_as(&file.hash, algo).map_err(io::Error::other)?;
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::parse_prefetched_file;
+    use crate::hash::{Hash, HashAlgo};
+
+    #[test]
+    fn prefetched_file() {
+        let json = br#"{
+            "hash": "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+            "storePath": "/nix/store/00000000000000000000000000000000-empty"
+        }"#;
+        let hash = parse_prefetched_file(json, HashAlgo::Sha256).unwrap();
+        assert_eq!(hash, Hash::compute(HashAlgo::Sha256, b""));
+
+        let err = parse_prefetched_file(json, HashAlgo::Sha512).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        let err = parse_prefetched_file(b"{}", HashAlgo::Sha256).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    // A `file://` URL goes through the same fetcher without needing a network.
+    #[cfg(feature = "nix-cli")]
+    #[test]
+    fn nix_cli_prefetch_url() {
+        use url::Url;
+
+        use super::prefetch_url;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hello.txt");
+        std::fs::write(&path, "hello").unwrap();
+        let url = Url::from_file_path(&path).unwrap();
+
+        for algo in [HashAlgo::Sha256, HashAlgo::Sha512] {
+            let hash = match prefetch_url(&url, algo) {
+                Ok(hash) => hash,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    eprintln!("skipping, `nix` is not on PATH");
+                    return;
+                }
+                Err(e) => panic!("failed to prefetch {url}: {e}"),
+            };
+            assert_eq!(hash, Hash::compute(algo, "hello"));
+        }
+    }
+}
@@ -0,0 +1,57 @@
+#[cfg(unix)]
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExitStatusError(pub ExitStatus);
+
+impl std::error::Error for ExitStatusError {}
+
+impl std::fmt::Display for ExitStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(code) = self.0.code() {
+            return write!(f, "exited with code: {code}");
+        }
+        // Only Unix processes are killed by signals.
+        #[cfg(unix)]
+        if let Some(signal) = self.0.signal() {
+            return write!(f, "killed by signal: {signal}");
+        }
+        write!(f, "exited with status: {}", self.0)
+    }
+}
+
+pub fn check_status(status: ExitStatus) -> std::io::Result<()> {
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::other(ExitStatusError(status)))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    use super::{ExitStatusError, check_status};
+
+    #[test]
+    fn display() {
+        let exited = ExitStatusError(ExitStatus::from_raw(3 << 8));
+        assert_eq!(exited.to_string(), "exited with code: 3");
+        let killed = ExitStatusError(ExitStatus::from_raw(9));
+        assert_eq!(killed.to_string(), "killed by signal: 9");
+        // a stopped process has neither an exit code nor a terminating signal
+        let stopped = ExitStatusError(ExitStatus::from_raw(0x137f));
+        assert!(stopped.to_string().starts_with("exited with status: "));
+    }
+
+    #[test]
+    fn check() {
+        assert!(check_status(ExitStatus::from_raw(0)).is_ok());
+        let err = check_status(ExitStatus::from_raw(1 << 8)).unwrap_err();
+        let inner = err.get_ref().unwrap().downcast_ref::<ExitStatusError>();
+        assert_eq!(inner, Some(&ExitStatusError(ExitStatus::from_raw(1 << 8))));
+    }
+}
@@ -0,0 +1,322 @@
+use digest::Digest;
+use sha2::Sha256;
+
+use crate::hash::{Hash, HashAlgo, HashFormat, decode_nix32, encode_nix32};
+
+pub const STORE_DIR: &str = "/nix/store";
+pub const HASH_PART_SIZE: usize = 20;
+const HASH_PART_LEN: usize = 32;
+const MAX_NAME_LEN: usize = 211;
+
+// <https://github.com/NixOS/nix/blob/c9211b0b2d52a26ed666780b763b39a5bddd3fb3/src/libstore/include/nix/store/path.hh>
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct StorePath {
+    digest: [u8; HASH_PART_SIZE],
+    name: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileIngestionMethod {
+    Flat,
+    Recursive,
+}
+
+#[derive(Clone, Debug, PartialEq, thiserror::Error)]
+pub enum StorePathError {
+    #[error("path `{0}` is not in the Nix store `{STORE_DIR}`")]
+    NotInStore(String),
+    #[error("store path `{0}` does not begin with a valid {HASH_PART_LEN} character hash")]
+    InvalidHashPart(String),
+    #[error("store path name `{0}` is empty, too long, or contains invalid characters")]
+    InvalidName(String),
+    #[error("a store path of this kind requires a `sha256` hash, found `{0}`")]
+    UnsupportedHash(HashAlgo),
+    #[error("a fixed-output store path with this ingestion method cannot have references")]
+    UnexpectedReferences,
+}
+
+impl StorePath {
+    pub fn hash_part(&self) -> String {
+        let mut buf = String::with_capacity(HASH_PART_LEN);
+        encode_nix32(&self.digest, &mut buf).unwrap();
+        buf
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // <https://github.com/NixOS/nix/blob/c9211b0b2d52a26ed666780b763b39a5bddd3fb3/src/libstore/store-api.cc#L144-L153>
+    pub fn make(r#type: &str, hash: &Hash, name: &str) -> Result<Self, StorePathError> {
+        validate_name(name)?;
+        let fingerprint = format!(
+            "{type}:{hash}:{STORE_DIR}:{name}",
+            hash = hash.to_string(&HashFormat::Base16, true)
+        );
+        let inner = Sha256::digest(fingerprint.as_bytes());
+        Ok(Self {
+            digest: compress_hash(inner.as_slice()),
+            name: name.to_string(),
+        })
+    }
+
+    pub fn text<'a>(
+        name: &str,
+        content_hash: &Hash,
+        references: impl IntoIterator<Item = &'a StorePath>,
+    ) -> Result<Self, StorePathError> {
+        if content_hash.algorithm() != HashAlgo::Sha256 {
+            return Err(StorePathError::UnsupportedHash(content_hash.algorithm()));
+        }
+        Self::make(&make_type("text", references, false), content_hash, name)
+    }
+
+    pub fn fixed_output<'a>(
+        name: &str,
+        hash: &Hash,
+        method: FileIngestionMethod,
+        references: impl IntoIterator<Item = &'a StorePath>,
+    ) -> Result<Self, StorePathError> {
+        if method == FileIngestionMethod::Recursive && hash.algorithm() == HashAlgo::Sha256 {
+            return Self::make(&make_type("source", references, false), hash, name);
+        }
+        if references.into_iter().next().is_some() {
+            return Err(StorePathError::UnexpectedReferences);
+        }
+        let fingerprint = format!(
+            "fixed:out:{method}{hash}:",
+            method = match method {
+                FileIngestionMethod::Flat => "",
+                FileIngestionMethod::Recursive => "r:",
+            },
+            hash = hash.to_string(&HashFormat::Base16, true),
+        );
+        let digest = Sha256::new_with_prefix(fingerprint.as_bytes());
+        let inner = Hash::from_digest(HashAlgo::Sha256, digest).unwrap();
+        Self::make("output:out", &inner, name)
+    }
+}
+
+impl std::str::FromStr for StorePath {
+    type Err = StorePathError;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        let base_name = path
+            .strip_prefix(STORE_DIR)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .ok_or_else(|| StorePathError::NotInStore(path.to_string()))?;
+        let (hash_part, name) = match base_name.as_bytes().get(HASH_PART_LEN) {
+            Some(b'-') => base_name.split_at(HASH_PART_LEN),
+            _ => return Err(StorePathError::InvalidHashPart(path.to_string())),
+        };
+        let mut digest = [0; HASH_PART_SIZE];
+        decode_nix32(hash_part.as_bytes(), &mut digest)
+            .map_err(|_| StorePathError::InvalidHashPart(path.to_string()))?;
+        let name = &name[1..];
+        validate_name(name)?;
+        Ok(Self {
+            digest,
+            name: name.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for StorePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{STORE_DIR}/")?;
+        encode_nix32(&self.digest, &mut *f)?;
+        write!(f, "-{}", self.name)
+    }
+}
+
+fn make_type<'a>(
+    r#type: &str,
+    references: impl IntoIterator<Item = &'a StorePath>,
+    self_reference: bool,
+) -> String {
+    let mut references = references
+        .into_iter()
+        .map(StorePath::to_string)
+        .collect::<Vec<_>>();
+    references.sort();
+    let mut r#type = r#type.to_string();
+    for reference in references {
+        r#type.push(':');
+        r#type.push_str(&reference);
+    }
+    if self_reference {
+        r#type.push_str(":self");
+    }
+    r#type
+}
+
+fn compress_hash(bytes: &[u8]) -> [u8; HASH_PART_SIZE] {
+    let mut compressed = [0; HASH_PART_SIZE];
+    for (i, byte) in bytes.iter().enumerate() {
+        compressed[i % HASH_PART_SIZE] ^= byte;
+    }
+    compressed
+}
+
+fn validate_name(name: &str) -> Result<(), StorePathError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"+-._?=".contains(&c));
+    if valid {
+        Ok(())
+    } else {
+        Err(StorePathError::InvalidName(name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::{FileIngestionMethod, StorePath, StorePathError};
+    use crate::hash::{Hash, HashAlgo};
+
+    // Expected paths were computed independently from the algorithm in `store-api.cc`.
+    static HELLO_SHA256: &str =
+        "sha256:d9014c4624844aa5bac314773d6b689ad467fa4e1d1a50a1b8a99d5a95f72ff5";
+
+    #[test]
+    fn text() {
+        let content = Hash::parse(HELLO_SHA256).unwrap();
+        let hello = StorePath::text("hello.txt", &content, []).unwrap();
+        assert_eq!(
+            hello.to_string(),
+            "/nix/store/i3vl5f9f521bladwcs3zi5gmc1pd6qr6-hello.txt"
+        );
+        assert_eq!(hello.hash_part(), "i3vl5f9f521bladwcs3zi5gmc1pd6qr6");
+        assert_eq!(hello.name(), "hello.txt");
+
+        let content =
+            Hash::parse("sha256:a8076d3d28d21e02012b20eaf7dbf75409a6277134439025f282e368e3305abf")
+                .unwrap();
+        assert_eq!(
+            StorePath::text("script.sh", &content, [&hello]).map(|p| p.to_string()),
+            Ok("/nix/store/gk4w5x7gb8f50r7dza51j7wvwp13421m-script.sh".into())
+        );
+    }
+
+    #[test_case(
+        HELLO_SHA256, FileIngestionMethod::Flat
+        => "/nix/store/6g99pcrpapn5x08wpcyiq20wj4qgml24-hello.txt"
+        ; "flat sha256"
+    )]
+    #[test_case(
+        "md5:746308829575e17c3331bbcb00c0898b", FileIngestionMethod::Flat
+        => "/nix/store/k0k3icc1d4zax821hg1vykbis98d79jm-hello.txt"
+        ; "flat md5"
+    )]
+    fn fixed_output_flat(hash: &str, method: FileIngestionMethod) -> String {
+        let hash = Hash::parse(hash).unwrap();
+        StorePath::fixed_output("hello.txt", &hash, method, [])
+            .unwrap()
+            .to_string()
+    }
+
+    #[test_case(
+        "sha256:c1a3d0ab649821db54ec4dc668c63489ea17738ca1727ed2638a230ede605e3f"
+        => "/nix/store/y425wzqa1awmgjg503bywqlj7lzdi6d9-source"
+        ; "recursive sha256"
+    )]
+    #[test_case(
+        "sha1:e7e3dd57bde4f64e46f51b85cbebdd9220258aa8"
+        => "/nix/store/50bnl6znbdhs1n27ik5sd4xscc5aw61x-source"
+        ; "recursive sha1"
+    )]
+    fn fixed_output_recursive(hash: &str) -> String {
+        let hash = Hash::parse(hash).unwrap();
+        StorePath::fixed_output("source", &hash, FileIngestionMethod::Recursive, [])
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn fixed_output_references() {
+        let hash = Hash::parse(HELLO_SHA256).unwrap();
+        let hello = StorePath::text("hello.txt", &hash, []).unwrap();
+        assert_eq!(
+            StorePath::fixed_output("hello.txt", &hash, FileIngestionMethod::Flat, [&hello]),
+            Err(StorePathError::UnexpectedReferences)
+        );
+    }
+
+    #[test_case("" ; "empty")]
+    #[test_case(".hidden" ; "leading dot")]
+    #[test_case("with space" ; "space")]
+    #[test_case("with/slash" ; "slash")]
+    fn invalid_name(name: &str) {
+        let hash = Hash::parse(HELLO_SHA256).unwrap();
+        assert_eq!(
+            StorePath::text(name, &hash, []),
+            Err(StorePathError::InvalidName(name.into()))
+        );
+    }
+
+    #[test]
+    fn parse() {
+        let path = "/nix/store/i3vl5f9f521bladwcs3zi5gmc1pd6qr6-hello-world-1.0.txt";
+        let parsed = path.parse::<StorePath>().unwrap();
+        assert_eq!(parsed.hash_part(), "i3vl5f9f521bladwcs3zi5gmc1pd6qr6");
+        assert_eq!(parsed.name(), "hello-world-1.0.txt");
+        assert_eq!(parsed.to_string(), path);
+
+        let hash = Hash::parse(HELLO_SHA256).unwrap();
+        let computed = StorePath::text("hello.txt", &hash, []).unwrap();
+        assert_eq!(computed.to_string().parse(), Ok(computed));
+    }
+
+    #[test_case(
+        "i3vl5f9f521bladwcs3zi5gmc1pd6qr6-hello.txt"
+        => StorePathError::NotInStore("i3vl5f9f521bladwcs3zi5gmc1pd6qr6-hello.txt".into())
+        ; "missing store dir"
+    )]
+    #[test_case(
+        "/gnu/store/i3vl5f9f521bladwcs3zi5gmc1pd6qr6-hello.txt"
+        => StorePathError::NotInStore("/gnu/store/i3vl5f9f521bladwcs3zi5gmc1pd6qr6-hello.txt".into())
+        ; "other store dir"
+    )]
+    #[test_case(
+        "/nix/store/i3vl5f9f521bladwcs3zi5gmc1pd6qr-hello.txt"
+        => StorePathError::InvalidHashPart("/nix/store/i3vl5f9f521bladwcs3zi5gmc1pd6qr-hello.txt".into())
+        ; "short hash part"
+    )]
+    #[test_case(
+        "/nix/store/i3vl5f9f521bladwcs3zi5gmc1pd6qr6x-hello.txt"
+        => StorePathError::InvalidHashPart("/nix/store/i3vl5f9f521bladwcs3zi5gmc1pd6qr6x-hello.txt".into())
+        ; "long hash part"
+    )]
+    #[test_case(
+        "/nix/store/i3vl5f9f521bladwcs3zi5gmc1pd6qre-hello.txt"
+        => StorePathError::InvalidHashPart("/nix/store/i3vl5f9f521bladwcs3zi5gmc1pd6qre-hello.txt".into())
+        ; "invalid hash symbol"
+    )]
+    #[test_case(
+        "/nix/store/i3vl5f9f521bladwcs3zi5gmc1pd6qr6"
+        => StorePathError::InvalidHashPart("/nix/store/i3vl5f9f521bladwcs3zi5gmc1pd6qr6".into())
+        ; "missing name"
+    )]
+    #[test_case(
+        "/nix/store/i3vl5f9f521bladwcs3zi5gmc1pd6qr6-hello.txt/bin"
+        => StorePathError::InvalidName("hello.txt/bin".into())
+        ; "subpath"
+    )]
+    fn parse_invalid(path: &str) -> StorePathError {
+        path.parse::<StorePath>().unwrap_err()
+    }
+
+    #[test]
+    fn text_requires_sha256() {
+        let hash = Hash::parse("md5:746308829575e17c3331bbcb00c0898b").unwrap();
+        assert_eq!(
+            StorePath::text("hello.txt", &hash, []),
+            Err(StorePathError::UnsupportedHash(HashAlgo::Md5))
+        );
+    }
+}
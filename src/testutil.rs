@@ -0,0 +1,17 @@
+// Helpers for tests of crates that depend on this one.
+
+use crate::hash::{Hash, HashAlgo};
+
+/// Digests the UTF-8 bytes of `s`.
+///
+/// ```
+/// use nixapi::hash::{Hash, HashAlgo};
+/// use nixapi::testutil::hash_str;
+///
+/// let empty = Hash::parse("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=").unwrap();
+/// assert_eq!(hash_str("", HashAlgo::Sha256), empty);
+/// assert!(hash_str("hello", HashAlgo::Sha1).matches_input("sha1:aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d"));
+/// ```
+pub fn hash_str(s: &str, algo: HashAlgo) -> Hash {
+    Hash::compute(algo, s)
+}